@@ -0,0 +1,17 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use toddler_copilot_extension::messages::{ChatRequest, ParseMode};
+
+// Parsing must never panic, and what is parsed must be serialized back to the same request.
+fuzz_target!(|data: &[u8]| {
+    let Ok(content) = std::str::from_utf8(data) else {
+        return;
+    };
+    let _ = ChatRequest::parse_with_mode(content, ParseMode::Strict);
+    if let Ok(request) = ChatRequest::parse(content) {
+        let json = serde_json::to_string(&request).expect("a parsed request is serializable");
+        let reparsed = ChatRequest::parse(&json).expect("a serialized request parses again");
+        assert_eq!(reparsed, request);
+    }
+});
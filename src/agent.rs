@@ -1,36 +1,324 @@
-use crate::copilot_public_keys::VerifyFromStr;
+use crate::events;
+use crate::github::GithubUser;
+use crate::messages::{ChatRequest, ThreadKey};
+use crate::redaction::redact_headers;
 use crate::state::AppState;
-use axum::extract::State;
+use crate::thread_store::{in_reply_to, next_turn, ThreadEntry, ThreadStore, ToolResult};
+use axum::extract::{Path, State};
 use axum::http::{HeaderMap, StatusCode};
-use base64::prelude::*;
-use tracing::{debug, error, warn};
+use axum::response::sse::Event;
+use axum::response::Response;
+use futures::future::BoxFuture;
+use futures::stream::{self, BoxStream};
+use futures::{Stream, StreamExt};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+use tracing::{debug, error, info, instrument, warn, Span};
 
+/// What an agent streams back to the Copilot client.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AgentEvent {
+    /// Chunk of the Markdown answer
+    Text(String),
+    /// Error displayed in place of the answer, the code identifying its kind
+    Error { code: String, message: String },
+    /// Result of a tool called while answering, recorded in the thread but not sent to the client
+    ToolResult(ToolResult),
+}
+
+impl AgentEvent {
+    fn into_event(self) -> Option<Event> {
+        match self {
+            Self::Text(content) => Some(events::text(&content)),
+            Self::Error { code, message } => Some(events::error(&code, &message)),
+            Self::ToolResult(_) => None,
+        }
+    }
+}
+
+pub type AgentEvents = BoxStream<'static, AgentEvent>;
+
+/// Answer made of a single Markdown message.
+pub fn message(markdown: impl Into<String>) -> AgentEvents {
+    stream::once(std::future::ready(AgentEvent::Text(markdown.into()))).boxed()
+}
+
+/// A request whose signature is verified, with its caller identified.
+pub struct AgentContext {
+    pub state: AppState,
+    pub request: ChatRequest,
+    /// Key of the conversation, see [`ChatRequest::thread_key`]
+    pub thread_key: ThreadKey,
+    pub github_token: String,
+    pub user: GithubUser,
+    pub integration_id: Option<String>,
+}
+
+/// Why an agent could not start answering, mapped to the status of the response.
+#[derive(Debug)]
+pub enum AgentError {
+    /// A service the agent relies on failed, answered with `502 Bad Gateway`
+    Upstream(anyhow::Error),
+    /// Answered with `500 Internal Server Error`
+    Internal(anyhow::Error),
+}
+
+impl AgentError {
+    #[must_use]
+    pub const fn status(&self) -> StatusCode {
+        match self {
+            Self::Upstream(_) => StatusCode::BAD_GATEWAY,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl Display for AgentError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Upstream(err) => write!(f, "upstream failure: {err:#}"),
+            Self::Internal(err) => write!(f, "internal failure: {err:#}"),
+        }
+    }
+}
+
+impl std::error::Error for AgentError {}
+
+/// A Copilot extension hosted by this deployment.
+///
+/// Signature verification, parsing, identification of the caller and streaming are done once for all agents.
+pub trait CopilotAgent: Send + Sync {
+    /// Name matched against the `agent` field of requests, and mounting the agent at `/agent/{name}`
+    fn name(&self) -> &str;
+
+    /// Starts answering, the events being streamed to the client as they come.
+    fn respond(&self, context: AgentContext) -> BoxFuture<'_, Result<AgentEvents, AgentError>>;
+}
+
+/// Agents hosted by this deployment, by name.
+#[derive(Clone)]
+pub struct AgentRegistry {
+    agents: Arc<HashMap<String, Arc<dyn CopilotAgent>>>,
+    default: Arc<dyn CopilotAgent>,
+}
+
+impl AgentRegistry {
+    /// Registry answering with the given agent the requests sent to `/agent` and addressed to no other agent.
+    pub fn new(default: impl CopilotAgent + 'static) -> Self {
+        let default: Arc<dyn CopilotAgent> = Arc::new(default);
+        Self {
+            agents: Arc::new(HashMap::from([(
+                default.name().to_string(),
+                default.clone(),
+            )])),
+            default,
+        }
+    }
+
+    /// Registers another agent, replacing any agent of the same name, the default one included.
+    #[must_use]
+    pub fn with_agent(mut self, agent: impl CopilotAgent + 'static) -> Self {
+        let agent: Arc<dyn CopilotAgent> = Arc::new(agent);
+        if agent.name() == self.default.name() {
+            self.default = agent.clone();
+        }
+        Arc::make_mut(&mut self.agents).insert(agent.name().to_string(), agent);
+        self
+    }
+
+    /// Agent mounted at the given path, `None` when no agent has this name.
+    #[must_use]
+    pub fn mounted(&self, name: &str) -> Option<Arc<dyn CopilotAgent>> {
+        self.agents.get(name).cloned()
+    }
+
+    /// Agent named by the `agent` field of the request, falling back to the default agent.
+    #[must_use]
+    pub fn requested(&self, name: Option<&str>) -> Arc<dyn CopilotAgent> {
+        name.and_then(|name| self.agents.get(name))
+            .unwrap_or(&self.default)
+            .clone()
+    }
+}
+
+#[instrument(
+    name = "agent",
+    skip_all,
+    fields(agent, copilot_thread_id, thread_key, integration_id, login)
+)]
 pub async fn chat_completion(
     State(state): State<AppState>,
+    mounted: Option<Path<String>>,
     headers: HeaderMap,
     body: String,
-) -> Result<String, StatusCode> {
-    let b64_body = BASE64_STANDARD.encode(&body);
-    debug!("{headers:#?}\n\n{b64_body:#?}");
-    let (_github_token, _integration_id) =
-        extract_header_and_verify_signature(&state, &headers, &body)?;
-    Ok("toto".to_string())
+) -> Result<Response, StatusCode> {
+    debug!(headers = ?redact_headers(&headers), "[http] chat_completion: Received request");
+    if state.config.log_request_bodies {
+        info!(body = %state.redactor.redact_body(&body), "[http] chat_completion: Request body");
+    }
+    let (github_token, integration_id) =
+        extract_header_and_verify_signature(&state, &headers, &body).await?;
+    Span::current().record("integration_id", integration_id.as_deref());
+    // unknown paths are rejected before the request is captured or observed
+    let mounted = match &mounted {
+        Some(Path(name)) => Some(state.agents.mounted(name).ok_or_else(|| {
+            warn!(
+                mounted = name,
+                "[http] chat_completion: No agent mounted at this path"
+            );
+            StatusCode::NOT_FOUND
+        })?),
+        None => None,
+    };
+    state.metrics.agent_request(integration_id.as_deref());
+    if let Some(capture) = &state.capture {
+        capture.record(&headers, &body).await;
+    }
+    let mut request = ChatRequest::parse_with_mode(&body, state.config.request_parse_mode)
+        .map_err(|err| {
+            state.metrics.parse_failure();
+            error!(error = ?err, "[http] chat_completion: Unable to parse request body");
+            StatusCode::BAD_REQUEST
+        })?;
+    for diagnostic in request.diagnostics() {
+        debug!(%diagnostic, "[http] chat_completion: Reference kept as unknown");
+    }
+    state.schema_drift.observe(&body, &request, &state.metrics);
+    Span::current().record("copilot_thread_id", request.copilot_thread_id.as_str());
+    let agent = mounted.unwrap_or_else(|| state.agents.requested(request.agent.as_deref()));
+    Span::current().record("agent", agent.name());
+    let user = state
+        .github_client
+        .user(&github_token)
+        .await
+        .map_err(|err| {
+            error!(error = ?err, "[http] chat_completion: Unable to identify the caller");
+            identification_status(&err)
+        })?;
+    Span::current().record("login", user.login.as_str());
+    let thread_key = request.thread_key(user.id, integration_id.as_deref());
+    Span::current().record("thread_key", thread_key.as_str());
+    match state
+        .threads
+        .merge(user.id, thread_key.as_str(), &mut request)
+        .await
+    {
+        Ok(0) => {}
+        Ok(restored) => debug!(
+            restored,
+            "[http] chat_completion: Answers restored from the thread"
+        ),
+        Err(err) => {
+            warn!(error = ?err, "[http] chat_completion: Unable to restore answers from the thread");
+        }
+    }
+
+    let entry = ThreadEntry {
+        turn: next_turn(&request),
+        in_reply_to: in_reply_to(&request),
+        agent: agent.name().to_string(),
+        metadata: integration_id
+            .iter()
+            .map(|integration_id| ("integration_id".to_string(), integration_id.clone()))
+            .collect(),
+        ..ThreadEntry::default()
+    };
+    let threads = state.threads.clone();
+    let user_id = user.id;
+    let context = AgentContext {
+        state,
+        request,
+        thread_key: thread_key.clone(),
+        github_token,
+        user,
+        integration_id,
+    };
+    let events = agent.respond(context).await.map_err(|err| {
+        error!(error = %err, "[http] chat_completion: Agent unable to answer");
+        err.status()
+    })?;
+    Ok(events::stream_response(record_answer(
+        threads, user_id, thread_key, entry, events,
+    )))
+}
+
+/// Forwards the events of an answer, recording it in the thread once it is entirely streamed.
+fn record_answer(
+    threads: ThreadStore,
+    user_id: u64,
+    thread_key: ThreadKey,
+    entry: ThreadEntry,
+    events: AgentEvents,
+) -> impl Stream<Item = Event> {
+    let entry = Arc::new(Mutex::new(entry));
+    let recorded = entry.clone();
+    events
+        .filter_map(move |event| {
+            let mut entry = entry
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner);
+            match &event {
+                AgentEvent::Text(content) => entry.content.push_str(content),
+                AgentEvent::ToolResult(result) => entry.tool_results.push(result.clone()),
+                AgentEvent::Error { .. } => {}
+            }
+            std::future::ready(event.into_event())
+        })
+        .chain(
+            stream::once(async move {
+                let entry = recorded
+                    .lock()
+                    .unwrap_or_else(std::sync::PoisonError::into_inner)
+                    .clone();
+                if let Err(err) = threads.record(user_id, thread_key.as_str(), &entry).await {
+                    error!(error = ?err, "[http] chat_completion: Unable to record the answer");
+                }
+                None
+            })
+            .filter_map(std::future::ready),
+        )
+}
+
+/// Status answered when the caller cannot be identified: `401 Unauthorized` when GitHub rejected the token,
+/// `502 Bad Gateway` when GitHub could not be reached or failed.
+fn identification_status(err: &anyhow::Error) -> StatusCode {
+    let rejected = err
+        .downcast_ref::<reqwest::Error>()
+        .and_then(reqwest::Error::status)
+        .is_some_and(|status| status == reqwest::StatusCode::UNAUTHORIZED);
+    if rejected {
+        StatusCode::UNAUTHORIZED
+    } else {
+        StatusCode::BAD_GATEWAY
+    }
 }
 
-fn extract_header_and_verify_signature(
+async fn extract_header_and_verify_signature(
     state: &AppState,
     headers: &HeaderMap,
     body: &str,
 ) -> Result<(String, Option<String>), StatusCode> {
     if let Some(signature) = headers.get("github-public-key-signature") {
         let raw_sig = signature.to_str().map_err(|err| {
+            state.metrics.signature_failure("malformed");
             error!(error = ?err, "[http] chat_completion: Unable to read header 'github-public-key-signature'");
             StatusCode::BAD_REQUEST
         })?;
+        let key_identifier = match headers.get("github-public-key-identifier") {
+            Some(v) => Some(v.to_str().map_err(|err| {
+                state.metrics.signature_failure("malformed");
+                error!(error = ?err, "[http] chat_completion: Unable to read header 'github-public-key-identifier'");
+                StatusCode::BAD_REQUEST
+            })?),
+            None => None,
+        };
         state
-            .copilot_public_key
-            .verify_from_str(raw_sig, body)
+            .copilot_public_keys
+            .verify(key_identifier, raw_sig, body)
+            .await
             .map_err(|err| {
+                state.metrics.signature_failure(err.reason());
                 error!(error = ?err, "[http] chat_completion: Invalid signature: {err:#?}");
                 StatusCode::BAD_REQUEST
             })?;
@@ -55,7 +343,96 @@ fn extract_header_and_verify_signature(
             Err(StatusCode::BAD_REQUEST)
         }
     } else {
+        state.metrics.signature_failure("missing_header");
         warn!("[http] chat_completion: Missing header 'github-public-key-signature'");
         Err(StatusCode::BAD_REQUEST)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::agent::{
+        identification_status, AgentContext, AgentError, AgentEvents, AgentRegistry, CopilotAgent,
+    };
+    use crate::github::GithubClient;
+    use axum::http::header::AUTHORIZATION;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::get;
+    use axum::Router;
+    use futures::future::BoxFuture;
+    use std::sync::Arc;
+
+    struct NamedAgent(&'static str);
+
+    impl CopilotAgent for NamedAgent {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn respond(&self, _: AgentContext) -> BoxFuture<'_, Result<AgentEvents, AgentError>> {
+            Box::pin(async { Ok(crate::agent::message(self.0)) })
+        }
+    }
+
+    #[test]
+    fn agents_are_resolved_by_path_then_by_agent_field() {
+        let registry = AgentRegistry::new(NamedAgent("toddler")).with_agent(NamedAgent("reviewer"));
+        let mounted = |name| registry.mounted(name).map(|agent| agent.name().to_string());
+        let requested = |name| registry.requested(name).name().to_string();
+        assert_eq!(mounted("reviewer").as_deref(), Some("reviewer"));
+        assert_eq!(mounted("toddler").as_deref(), Some("toddler"));
+        assert_eq!(mounted("unknown"), None);
+        assert_eq!(requested(Some("reviewer")), "reviewer");
+        // the agent field carries the slug of the GitHub App, which may not match any agent name
+        assert_eq!(requested(Some("mais-arreeeeeeeeteuuuu")), "toddler");
+        assert_eq!(requested(None), "toddler");
+    }
+
+    #[test]
+    fn replacing_the_default_agent_replaces_it_everywhere() -> anyhow::Result<()> {
+        let registry = AgentRegistry::new(NamedAgent("toddler")).with_agent(NamedAgent("toddler"));
+        let by_path = registry
+            .mounted("toddler")
+            .ok_or_else(|| anyhow::anyhow!("no agent mounted at toddler"))?;
+        assert!(Arc::ptr_eq(&registry.requested(None), &by_path));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_rejected_tokens_are_unauthorized() -> anyhow::Result<()> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let api_url = format!("http://{}", listener.local_addr()?);
+        let router = Router::new().route(
+            "/user",
+            get(|headers: HeaderMap| async move {
+                match headers
+                    .get(AUTHORIZATION)
+                    .and_then(|value| value.to_str().ok())
+                {
+                    Some("Bearer outage") => StatusCode::SERVICE_UNAVAILABLE,
+                    _ => StatusCode::UNAUTHORIZED,
+                }
+            }),
+        );
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let status = |api_url: String, token: &'static str| async move {
+            let err = GithubClient::new(&api_url)
+                .user(token)
+                .await
+                .err()
+                .ok_or_else(|| anyhow::anyhow!("user found"))?;
+            anyhow::Ok(identification_status(&err))
+        };
+        assert_eq!(
+            status(api_url.clone(), "revoked").await?,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(status(api_url, "outage").await?, StatusCode::BAD_GATEWAY);
+        assert_eq!(
+            status("http://127.0.0.1:1".to_string(), "any").await?,
+            StatusCode::BAD_GATEWAY
+        );
+        Ok(())
+    }
+}
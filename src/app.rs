@@ -0,0 +1,33 @@
+use crate::agent::chat_completion;
+use crate::device_flow::{poll_device_flow, start_device_flow};
+use crate::logout::logout;
+use crate::metrics::metrics;
+use crate::oauth::{post_auth, pre_auth};
+use crate::prompt_templates::reload_prompt_templates;
+use crate::request_tracing::with_request_tracing;
+use crate::schema_drift::schema_drift;
+use crate::state::AppState;
+use crate::webhooks::github_webhook;
+use axum::routing::{get, post};
+use axum::Router;
+
+/// Routes of the app, shared by all the entry points.
+pub fn router(state: AppState) -> Router {
+    let router = Router::new()
+        .route("/auth/authorization", get(pre_auth))
+        .route("/auth/callback", get(post_auth))
+        .route("/auth/device", post(start_device_flow))
+        .route("/auth/device/:flow_id", get(poll_device_flow))
+        .route("/auth/logout", post(logout))
+        .route("/agent", post(chat_completion))
+        .route("/agent/:agent", post(chat_completion))
+        .route("/webhooks/github", post(github_webhook))
+        .route("/metrics", get(metrics))
+        .route("/debug/schema-drift", get(schema_drift))
+        .route(
+            "/debug/prompt-templates/reload",
+            post(reload_prompt_templates),
+        )
+        .with_state(state);
+    with_request_tracing(router)
+}
@@ -0,0 +1,217 @@
+use anyhow::Context;
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use toddler_copilot_extension::app::router;
+use toddler_copilot_extension::config::Config;
+use toddler_copilot_extension::replay::{diff, ReplayPayload, ReplaySigner, Replayer};
+use toddler_copilot_extension::state::AppState;
+use tracing::info;
+
+/// Runs the extension as a plain axum server, without Shuttle.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// TOML file to read the configuration from, instead of the environment variables and the .env file
+    #[arg(long, env = "CONFIG_FILE", global = true)]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Starts the server (default)
+    Serve,
+    /// Validates the configuration, reporting every problem, without starting the server
+    CheckConfig,
+    /// Generates a key pair to sign replayed requests, the public keys file being trusted by the server through `TRUSTED_TEST_KEYS_FILE`
+    ReplayKeygen {
+        #[arg(long, default_value = "replay_key.pem")]
+        private_key: PathBuf,
+        #[arg(long, default_value = "replay_public_keys.json")]
+        public_keys: PathBuf,
+        #[arg(long, default_value = "replay")]
+        key_identifier: String,
+    },
+    /// Replays captured requests (.jsonl) or sample payloads against a running server, signed with a replay key
+    Replay(ReplayArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct ReplayArgs {
+    /// Base URL of the server to replay requests against
+    #[arg(long, env = "REPLAY_TARGET", default_value = "http://localhost:8000")]
+    target: String,
+    #[arg(long, default_value = "replay_key.pem")]
+    private_key: PathBuf,
+    #[arg(long, default_value = "replay")]
+    key_identifier: String,
+    /// Token sent as `x-github-token`, as captured ones are redacted
+    #[arg(long, env = "GITHUB_TOKEN", hide_env_values = true)]
+    github_token: String,
+    /// Directory holding the expected output of each payload, in `<name>.md`
+    #[arg(long)]
+    expected_dir: Option<PathBuf>,
+    /// Writes the actual outputs as the expected ones instead of comparing them
+    #[arg(long, requires = "expected_dir")]
+    write_expected: bool,
+    /// Capture files or sample payloads
+    #[arg(required = true)]
+    payloads: Vec<PathBuf>,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<ExitCode> {
+    let args = Args::parse();
+    match args.command.unwrap_or(Command::Serve) {
+        Command::Serve => {
+            let config = load_config(args.config.as_ref())?;
+            toddler_copilot_extension::tracing::setup(&config)?;
+            let served = serve(config).await;
+            toddler_copilot_extension::tracing::shutdown().await;
+            served?;
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::CheckConfig => Ok(check_config(args.config.as_ref())),
+        Command::ReplayKeygen {
+            private_key,
+            public_keys,
+            key_identifier,
+        } => {
+            ReplaySigner::generate(&key_identifier).write(&private_key, &public_keys)?;
+            println!(
+                "Private key written to {}, set TRUSTED_TEST_KEYS_FILE={} on the server to trust it",
+                private_key.display(),
+                public_keys.display()
+            );
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Replay(args) => replay(args).await,
+    }
+}
+
+async fn replay(args: ReplayArgs) -> anyhow::Result<ExitCode> {
+    let signer = ReplaySigner::from_pem_file(&args.private_key, &args.key_identifier)?;
+    let replayer = Replayer::new(&args.target, signer, args.github_token);
+    let mut failures = 0;
+    for path in &args.payloads {
+        for payload in ReplayPayload::read_all(path)? {
+            let outcome = match replayer.replay(&payload).await {
+                Ok(outcome) => outcome,
+                Err(err) => {
+                    println!("{}: {err:#}", payload.name);
+                    failures += 1;
+                    continue;
+                }
+            };
+            let expected_path = args
+                .expected_dir
+                .as_ref()
+                .map(|dir| dir.join(format!("{}.md", payload.name)));
+            let comparison = match &expected_path {
+                Some(expected_path) if args.write_expected => {
+                    std::fs::write(expected_path, &outcome.output)
+                        .with_context(|| format!("Unable to write {}", expected_path.display()))?;
+                    Ok("expected output written".to_string())
+                }
+                Some(expected_path) if expected_path.exists() => {
+                    let expected = std::fs::read_to_string(expected_path)?;
+                    diff(&expected, &outcome.output)
+                        .map_or_else(|| Ok("as expected".to_string()), Err)
+                }
+                _ => Ok("no expected output".to_string()),
+            };
+            let successful = (200..300).contains(&outcome.status);
+            println!(
+                "{}: {} in {} ms, {}",
+                payload.name,
+                outcome.status,
+                outcome.latency.as_millis(),
+                comparison
+                    .as_ref()
+                    .map_or("differs from expected output", String::as_str)
+            );
+            if let Err(diff) = &comparison {
+                println!("{diff}");
+            }
+            if !successful || comparison.is_err() {
+                failures += 1;
+            }
+        }
+    }
+    Ok(if failures == 0 {
+        ExitCode::SUCCESS
+    } else {
+        eprintln!("{failures} replay(s) failed");
+        ExitCode::FAILURE
+    })
+}
+
+async fn serve(config: Config) -> anyhow::Result<()> {
+    let bind_address = config.bind_address.clone();
+    let state = AppState::new(config).await?;
+
+    let listener = tokio::net::TcpListener::bind(&bind_address)
+        .await
+        .with_context(|| format!("[config] Unable to bind {bind_address}"))?;
+    info!("Listening on {}", listener.local_addr()?);
+    axum::serve(listener, router(state))
+        .with_graceful_shutdown(shutdown_signal())
+        .await?;
+    Ok(())
+}
+
+fn check_config(config_file: Option<&PathBuf>) -> ExitCode {
+    let result = load_config(config_file).and_then(|config| Ok(config.validate()?));
+    match result {
+        Ok(()) => {
+            println!("Configuration is valid");
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("{err:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn load_config(config_file: Option<&PathBuf>) -> anyhow::Result<Config> {
+    if let Some(path) = config_file {
+        return Config::from_toml_file(path);
+    }
+    match dotenvy::dotenv() {
+        Ok(path) => info!("Loaded environment from {}", path.display()),
+        Err(err) if err.not_found() => {}
+        Err(err) => return Err(err).context("[config] Unable to read .env file"),
+    }
+    Config::from_env()
+}
+
+/// Resolves on Ctrl+C, or on `SIGTERM` as sent by container runtimes, so that spans are flushed before exiting.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            tracing::error!(error = ?err, "Unable to listen for shutdown signal");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                tracing::error!(error = ?err, "Unable to listen for SIGTERM");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        () = ctrl_c => {}
+        () = terminate => {}
+    }
+}
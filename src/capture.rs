@@ -0,0 +1,289 @@
+use crate::config::Config;
+use crate::redaction::{redact_headers, Redactor};
+use anyhow::Context;
+use axum::http::HeaderMap;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tracing::{error, info};
+
+/// Headers stored apart in captured requests.
+const SIGNATURE_HEADER: &str = "github-public-key-signature";
+const KEY_IDENTIFIER_HEADER: &str = "github-public-key-identifier";
+
+/// An agent request as written in the capture file, one per line.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct CapturedRequest {
+    pub timestamp: String,
+    /// Headers of the request, with credentials redacted
+    pub headers: BTreeMap<String, String>,
+    /// Body of the request, with the fields matching the capture redaction rules redacted
+    pub body: serde_json::Value,
+    pub signature: String,
+    pub key_identifier: Option<String>,
+}
+
+impl CapturedRequest {
+    /// Reads every request of a capture file.
+    pub fn read_all(path: &Path) -> anyhow::Result<Vec<Self>> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("[capture] Unable to read {}", path.display()))?;
+        content
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .enumerate()
+            .map(|(index, line)| {
+                serde_json::from_str(line).with_context(|| {
+                    format!(
+                        "[capture] Invalid request at line {} of {}",
+                        index + 1,
+                        path.display()
+                    )
+                })
+            })
+            .collect()
+    }
+}
+
+/// Appends verified agent requests to a JSON Lines file, rotated when it grows past a size.
+///
+/// Rotated files are suffixed with `.1` (the most recent) up to `.{max_files}`, older ones are deleted.
+#[derive(Clone)]
+pub struct Capture {
+    file: Arc<Mutex<CaptureFile>>,
+    redactor: Redactor,
+}
+
+struct CaptureFile {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    writer: Option<File>,
+    size: u64,
+}
+
+impl Capture {
+    /// Capture configured by `CAPTURE_PATH`, if any.
+    pub fn from_config(config: &Config) -> anyhow::Result<Option<Self>> {
+        config
+            .capture_path
+            .as_deref()
+            .map(|path| {
+                Self::new(
+                    Path::new(path),
+                    config.capture_max_bytes,
+                    config.capture_max_files,
+                    Redactor::with_additional_paths(config.capture_redacted_json_paths.as_deref()),
+                )
+            })
+            .transpose()
+    }
+
+    pub fn new(
+        path: &Path,
+        max_bytes: u64,
+        max_files: usize,
+        redactor: Redactor,
+    ) -> anyhow::Result<Self> {
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("[capture] Unable to create {}", parent.display()))?;
+        }
+        info!(path = %path.display(), "[capture] Capturing agent requests");
+        Ok(Self {
+            file: Arc::new(Mutex::new(CaptureFile {
+                path: path.to_path_buf(),
+                max_bytes,
+                max_files,
+                writer: None,
+                size: 0,
+            })),
+            redactor,
+        })
+    }
+
+    /// Appends a request whose signature has been verified, logging failures rather than failing the request.
+    ///
+    /// The file is written, and rotated, on the blocking thread pool.
+    pub async fn record(&self, headers: &HeaderMap, body: &str) {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+        };
+        let mut redacted_headers = redact_headers(headers);
+        redacted_headers.remove(SIGNATURE_HEADER);
+        redacted_headers.remove(KEY_IDENTIFIER_HEADER);
+        let request = CapturedRequest {
+            timestamp: OffsetDateTime::now_utc()
+                .format(&Rfc3339)
+                .unwrap_or_default(),
+            headers: redacted_headers,
+            body: self.redactor.redact_body(body),
+            signature: header(SIGNATURE_HEADER).unwrap_or_default(),
+            key_identifier: header(KEY_IDENTIFIER_HEADER),
+        };
+        if let Err(err) = self.append(&request).await {
+            error!(error = ?err, "[capture] Unable to capture request");
+        }
+    }
+
+    async fn append(&self, request: &CapturedRequest) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(request)?;
+        line.push(b'\n');
+        let file = self.file.clone();
+        tokio::task::spawn_blocking(move || {
+            file.lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner)
+                .append(&line)
+        })
+        .await?
+    }
+}
+
+impl CaptureFile {
+    fn append(&mut self, line: &[u8]) -> anyhow::Result<()> {
+        self.open()?;
+        if self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.open()?.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn open(&mut self) -> anyhow::Result<&mut File> {
+        if self.writer.is_none() {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .with_context(|| format!("[capture] Unable to open {}", self.path.display()))?;
+            self.size = file.metadata()?.len();
+            self.writer = Some(file);
+        }
+        self.writer
+            .as_mut()
+            .context("[capture] Capture file not opened")
+    }
+
+    fn rotate(&mut self) -> anyhow::Result<()> {
+        self.writer = None;
+        let rotated = |index: usize| PathBuf::from(format!("{}.{index}", self.path.display()));
+        if self.max_files == 0 {
+            std::fs::remove_file(&self.path)?;
+            return Ok(());
+        }
+        let oldest = rotated(self.max_files);
+        if oldest.exists() {
+            std::fs::remove_file(&oldest)?;
+        }
+        for index in (1..self.max_files).rev() {
+            let from = rotated(index);
+            if from.exists() {
+                std::fs::rename(&from, rotated(index + 1))?;
+            }
+        }
+        std::fs::rename(&self.path, rotated(1))?;
+        info!(path = %self.path.display(), "[capture] Capture file rotated");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::capture::{Capture, CapturedRequest};
+    use crate::redaction::Redactor;
+    use axum::http::{HeaderMap, HeaderValue};
+    use std::path::PathBuf;
+
+    fn capture_dir(name: &str) -> anyhow::Result<PathBuf> {
+        let dir = std::env::temp_dir().join(format!("toddler_capture_{name}"));
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir)?;
+        }
+        Ok(dir)
+    }
+
+    fn headers() -> anyhow::Result<HeaderMap> {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-github-token", HeaderValue::from_static("ghu_secret"));
+        headers.insert(
+            "github-public-key-signature",
+            HeaderValue::from_static("MEYCIQ=="),
+        );
+        headers.insert(
+            "github-public-key-identifier",
+            HeaderValue::from_static("4fe6b016"),
+        );
+        headers.insert(
+            "copilot-integration-id",
+            HeaderValue::from_str("vscode-chat")?,
+        );
+        Ok(headers)
+    }
+
+    #[tokio::test]
+    async fn requests_are_captured_without_secrets() -> anyhow::Result<()> {
+        let dir = capture_dir("redaction")?;
+        let path = dir.join("requests.jsonl");
+        let capture = Capture::new(
+            &path,
+            1024 * 1024,
+            2,
+            Redactor::with_additional_paths(Some("messages.*.content")),
+        )?;
+        let body = r#"{"copilot_thread_id":"","messages":[{"role":"user","content":"my secret","copilot_references":[]}]}"#;
+        capture.record(&headers()?, body).await;
+
+        let captured = CapturedRequest::read_all(&path)?;
+        std::fs::remove_dir_all(&dir)?;
+        assert_eq!(captured.len(), 1);
+        let request = &captured[0];
+        assert_eq!(request.headers["x-github-token"], "[REDACTED]");
+        assert_eq!(request.headers["copilot-integration-id"], "vscode-chat");
+        assert!(!request.headers.contains_key("github-public-key-signature"));
+        assert_eq!(request.signature, "MEYCIQ==");
+        assert_eq!(request.key_identifier.as_deref(), Some("4fe6b016"));
+        assert_eq!(request.body["messages"][0]["content"], "[REDACTED]");
+        assert_eq!(request.body["messages"][0]["role"], "user");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn capture_file_is_rotated() -> anyhow::Result<()> {
+        let dir = capture_dir("rotation")?;
+        let path = dir.join("requests.jsonl");
+        // every captured request is larger than the limit, hence alone in its file
+        let capture = Capture::new(&path, 100, 2, Redactor::default())?;
+        for index in 0..5 {
+            capture
+                .record(&headers()?, &format!(r#"{{"index":{index}}}"#))
+                .await;
+        }
+
+        let indexes = |path: PathBuf| -> anyhow::Result<Vec<serde_json::Value>> {
+            Ok(CapturedRequest::read_all(&path)?
+                .into_iter()
+                .map(|request| request.body["index"].clone())
+                .collect())
+        };
+        let current = indexes(path.clone())?;
+        let previous = indexes(dir.join("requests.jsonl.1"))?;
+        let oldest = indexes(dir.join("requests.jsonl.2"))?;
+        let dropped = dir.join("requests.jsonl.3").exists();
+        std::fs::remove_dir_all(&dir)?;
+        assert_eq!(current, vec![4]);
+        assert_eq!(previous, vec![3]);
+        assert_eq!(oldest, vec![2]);
+        assert!(!dropped);
+        Ok(())
+    }
+}
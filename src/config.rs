@@ -1,3 +1,6 @@
+use crate::messages::ParseMode;
+use anyhow::anyhow;
+#[cfg(feature = "shuttle")]
 use shuttle_runtime::SecretStore;
 use std::collections::HashMap;
 
@@ -6,14 +9,215 @@ pub struct Config {
     pub base_url: String,
     pub github_app_client_id: String,
     pub github_app_client_secret: String,
+    #[serde(default)]
+    pub environment: Environment,
+    /// Comma-separated list of base64 encoded keys (at least 64 bytes each), newest first.
+    #[serde(default)]
+    pub cookie_keys: Option<String>,
+    #[serde(flatten)]
+    pub github: GithubConfig,
+    /// Secret configured on the GitHub app webhook, the webhook route is disabled without it.
+    #[serde(default)]
+    pub github_webhook_secret: Option<String>,
+    #[serde(default)]
+    pub log_format: LogFormat,
+    /// Logs the body of agent requests, after redaction, whatever the log level.
+    #[serde(default)]
+    pub log_request_bodies: bool,
+    /// Comma-separated list of JSON paths to redact from logged payloads, in addition to the default ones.
+    #[serde(default)]
+    pub redacted_json_paths: Option<String>,
+    /// How agent requests with unexpected references are handled, `lenient` keeps them as unknown ones.
+    #[serde(default)]
+    pub request_parse_mode: ParseMode,
+    /// Address the standalone server listens on, ignored on Shuttle.
+    #[serde(default = "default_bind_address")]
+    pub bind_address: String,
+    /// JSON file of public keys, in the format of the published ones, trusted on top of them to replay requests.
+    #[serde(default)]
+    pub trusted_test_keys_file: Option<String>,
+    /// File to which verified agent requests are appended, as JSON Lines, capture is disabled without it.
+    #[serde(default)]
+    pub capture_path: Option<String>,
+    /// Size past which the capture file is rotated.
+    #[serde(default = "default_capture_max_bytes")]
+    pub capture_max_bytes: u64,
+    /// Number of rotated capture files kept.
+    #[serde(default = "default_capture_max_files")]
+    pub capture_max_files: usize,
+    /// Comma-separated list of JSON paths to redact from captured bodies, in addition to the default ones.
+    #[serde(default)]
+    pub capture_redacted_json_paths: Option<String>,
+    /// `SQLite` database recording the answers of the extension by thread, kept in memory without it.
+    #[serde(default)]
+    pub thread_store_path: Option<String>,
+    /// Tokens of the prompts sent to the model, the history and references being trimmed to fit.
+    #[serde(default = "default_prompt_max_tokens")]
+    pub prompt_max_tokens: usize,
+    /// Directory of `<agent>.jinja` and `<agent>.<command>.jinja` system prompt templates, overriding the built-in ones.
+    #[serde(default)]
+    pub prompt_templates_dir: Option<String>,
+    /// Bearer token required to read `/metrics`, which is public without it.
+    #[serde(default)]
+    pub metrics_token: Option<String>,
+    /// Base URL of an OTLP/HTTP collector, traces are only exported when set.
+    #[serde(default)]
+    pub otel_exporter_otlp_endpoint: Option<String>,
+    #[serde(default = "default_otel_service_name")]
+    pub otel_service_name: String,
+    /// Share of the traces started here that are exported, between 0 and 1.
+    #[serde(default = "default_otel_sampling_ratio")]
+    pub otel_sampling_ratio: f64,
 }
 
+#[derive(serde::Deserialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Environment {
+    #[default]
+    Local,
+    Production,
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// Human readable lines, colored when written to a terminal
+    #[default]
+    Pretty,
+    /// One JSON object per line, for log aggregators
+    Json,
+}
+
+/// GitHub endpoints, pointing to github.com by default, to be changed for GitHub Enterprise Server or a mock.
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct GithubConfig {
+    /// Base URL of the web UI, hosting the OAuth endpoints
+    #[serde(rename = "github_web_url", default = "default_github_web_url")]
+    pub web_url: String,
+    /// Base URL of the REST API
+    #[serde(rename = "github_api_url", default = "default_github_api_url")]
+    pub api_url: String,
+    /// URL of the keys used to sign Copilot requests, defaults to the one of the REST API
+    #[serde(rename = "github_copilot_public_keys_url", default)]
+    pub copilot_public_keys_url: Option<String>,
+    /// Base URL of the Copilot API, answering the chat completions
+    #[serde(rename = "github_copilot_api_url", default)]
+    pub copilot_api_url: Option<String>,
+}
+
+fn default_github_web_url() -> String {
+    "https://github.com".to_string()
+}
+
+fn default_github_api_url() -> String {
+    "https://api.github.com".to_string()
+}
+
+impl GithubConfig {
+    #[must_use]
+    pub fn authorize_url(&self) -> String {
+        format!("{}/login/oauth/authorize", self.web_url)
+    }
+
+    #[must_use]
+    pub fn token_url(&self) -> String {
+        format!("{}/login/oauth/access_token", self.web_url)
+    }
+
+    #[must_use]
+    pub fn device_authorization_url(&self) -> String {
+        format!("{}/login/device/code", self.web_url)
+    }
+
+    #[must_use]
+    pub fn copilot_public_keys_url(&self) -> String {
+        self.copilot_public_keys_url
+            .clone()
+            .unwrap_or_else(|| format!("{}/meta/public_keys/copilot_api", self.api_url))
+    }
+
+    #[must_use]
+    pub fn copilot_api_url(&self) -> String {
+        self.copilot_api_url
+            .clone()
+            .unwrap_or_else(|| "https://api.githubcopilot.com".to_string())
+    }
+}
+
+fn default_bind_address() -> String {
+    "0.0.0.0:8000".to_string()
+}
+
+const fn default_capture_max_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+const fn default_capture_max_files() -> usize {
+    5
+}
+
+const fn default_prompt_max_tokens() -> usize {
+    64_000
+}
+
+fn default_otel_service_name() -> String {
+    "toddler-copilot-extension".to_string()
+}
+
+const fn default_otel_sampling_ratio() -> f64 {
+    1.0
+}
+
+/// Parameters without default value, as environment variables.
+const REQUIRED_PARAMETERS: [&str; 3] = [
+    "BASE_URL",
+    "GITHUB_APP_CLIENT_ID",
+    "GITHUB_APP_CLIENT_SECRET",
+];
+
+impl Config {
+    /// Reads the configuration from environment variables, named after the fields in upper case.
+    pub fn from_env() -> anyhow::Result<Self> {
+        Self::from_vars(std::env::vars())
+    }
+
+    /// Reads the configuration from variables named after the fields in upper case,
+    /// reporting all the missing required ones at once.
+    pub fn from_vars<I: IntoIterator<Item = (String, String)>>(vars: I) -> anyhow::Result<Self> {
+        let vars: Vec<(String, String)> = vars.into_iter().collect();
+        let missing: Vec<&str> = REQUIRED_PARAMETERS
+            .into_iter()
+            .filter(|parameter| !vars.iter().any(|(name, _)| name == parameter))
+            .collect();
+        if !missing.is_empty() {
+            return Err(anyhow!(
+                "[config] Missing required parameter(s): {}",
+                missing.join(", ")
+            ));
+        }
+        let config = envy::from_iter::<_, Self>(vars)?;
+        Ok(config)
+    }
+
+    /// Reads the configuration from a TOML file, with keys named after the fields.
+    #[cfg(feature = "standalone")]
+    pub fn from_toml_file(path: &std::path::Path) -> anyhow::Result<Self> {
+        use anyhow::Context;
+
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("[config] Unable to read {}", path.display()))?;
+        let config = toml::from_str(&content)
+            .with_context(|| format!("[config] Unable to parse {}", path.display()))?;
+        Ok(config)
+    }
+}
+
+#[cfg(feature = "shuttle")]
 impl TryFrom<SecretStore> for Config {
     type Error = anyhow::Error;
 
     fn try_from(value: SecretStore) -> Result<Self, Self::Error> {
-        let config = envy::from_iter::<_, Self>(value)?;
-        Ok(config)
+        Self::from_vars(value)
     }
 }
 
@@ -21,7 +225,96 @@ impl TryFrom<HashMap<String, String>> for Config {
     type Error = anyhow::Error;
 
     fn try_from(value: HashMap<String, String>) -> Result<Self, Self::Error> {
-        let config = envy::from_iter::<_, Self>(value)?;
-        Ok(config)
+        Self::from_vars(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Config, Environment};
+    use std::collections::HashMap;
+
+    #[test]
+    fn defaults_apply_to_optional_settings() -> anyhow::Result<()> {
+        let config = Config::try_from(HashMap::from([
+            ("BASE_URL".to_string(), "http://localhost:8000".to_string()),
+            ("GITHUB_APP_CLIENT_ID".to_string(), "Iv1.abc".to_string()),
+            ("GITHUB_APP_CLIENT_SECRET".to_string(), "secret".to_string()),
+        ]))?;
+        assert_eq!(config.environment, Environment::Local);
+        assert_eq!(
+            config.github.authorize_url(),
+            "https://github.com/login/oauth/authorize"
+        );
+        assert_eq!(
+            config.github.copilot_public_keys_url(),
+            "https://api.github.com/meta/public_keys/copilot_api"
+        );
+        assert_eq!(config.bind_address, "0.0.0.0:8000");
+        assert_eq!(config.otel_exporter_otlp_endpoint, None);
+        assert!((config.otel_sampling_ratio - 1.0).abs() < f64::EPSILON);
+        Ok(())
+    }
+
+    #[test]
+    fn all_missing_parameters_are_reported() {
+        let err = Config::try_from(HashMap::from([(
+            "GITHUB_APP_CLIENT_ID".to_string(),
+            "Iv1.abc".to_string(),
+        )]))
+        .err()
+        .map(|err| err.to_string());
+        assert_eq!(
+            err.as_deref(),
+            Some("[config] Missing required parameter(s): BASE_URL, GITHUB_APP_CLIENT_SECRET")
+        );
+    }
+
+    #[test]
+    fn github_endpoints_can_be_overridden() -> anyhow::Result<()> {
+        let config = Config::try_from(HashMap::from([
+            ("BASE_URL".to_string(), "http://localhost:8000".to_string()),
+            ("GITHUB_APP_CLIENT_ID".to_string(), "Iv1.abc".to_string()),
+            ("GITHUB_APP_CLIENT_SECRET".to_string(), "secret".to_string()),
+            (
+                "GITHUB_WEB_URL".to_string(),
+                "https://ghes.example.com".to_string(),
+            ),
+            (
+                "GITHUB_API_URL".to_string(),
+                "https://ghes.example.com/api/v3".to_string(),
+            ),
+        ]))?;
+        assert_eq!(
+            config.github.token_url(),
+            "https://ghes.example.com/login/oauth/access_token"
+        );
+        assert_eq!(
+            config.github.copilot_public_keys_url(),
+            "https://ghes.example.com/api/v3/meta/public_keys/copilot_api"
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "standalone")]
+    #[test]
+    fn from_toml_file() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join("toddler_config_from_toml_file.toml");
+        std::fs::write(
+            &path,
+            r#"
+base_url = "https://toddler.example.com"
+github_app_client_id = "Iv1.abc"
+github_app_client_secret = "secret"
+environment = "production"
+bind_address = "127.0.0.1:3000"
+"#,
+        )?;
+        let config = Config::from_toml_file(&path)?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(config.base_url, "https://toddler.example.com");
+        assert_eq!(config.environment, Environment::Production);
+        assert_eq!(config.bind_address, "127.0.0.1:3000");
+        Ok(())
     }
 }
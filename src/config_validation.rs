@@ -0,0 +1,339 @@
+use crate::config::{Config, Environment};
+use crate::cookie_keys::CookieKeys;
+use oauth2::url::Url;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+
+/// Every problem found in a configuration, so they can be fixed at once.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct ConfigErrors {
+    pub errors: Vec<ConfigError>,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct ConfigError {
+    /// Name of the parameter, as an environment variable
+    pub parameter: &'static str,
+    pub message: String,
+}
+
+impl Display for ConfigErrors {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[config] {} invalid parameter(s):", self.errors.len())?;
+        for error in &self.errors {
+            write!(f, "\n  - {}: {}", error.parameter, error.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+/// Collects configuration problems instead of stopping at the first one.
+#[derive(Default)]
+pub struct Validator {
+    errors: ConfigErrors,
+}
+
+impl Validator {
+    pub fn error<T: Into<String>>(&mut self, parameter: &'static str, message: T) {
+        self.errors.errors.push(ConfigError {
+            parameter,
+            message: message.into(),
+        });
+    }
+
+    pub fn not_blank(&mut self, parameter: &'static str, value: &str) {
+        if value.trim().is_empty() {
+            self.error(parameter, "must not be blank");
+        }
+    }
+
+    /// Checks that the value is an absolute http(s) URL, to which paths can be appended.
+    pub fn base_url(&mut self, parameter: &'static str, value: &str) -> Option<Url> {
+        let url = match Url::parse(value) {
+            Ok(url) => url,
+            Err(err) => {
+                self.error(
+                    parameter,
+                    format!("'{value}' is not a valid URL ({err}), expected something like https://example.com"),
+                );
+                return None;
+            }
+        };
+        if !matches!(url.scheme(), "http" | "https") {
+            self.error(parameter, format!("'{value}' must use http or https"));
+        } else if value.ends_with('/') || url.query().is_some() || url.fragment().is_some() {
+            self.error(
+                parameter,
+                format!("'{value}' must not end with '/' nor contain a query or a fragment"),
+            );
+        }
+        Some(url)
+    }
+
+    /// Checks that the value is an absolute http(s) URL.
+    pub fn url(&mut self, parameter: &'static str, value: &str) {
+        match Url::parse(value) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+            Ok(_) => self.error(parameter, format!("'{value}' must use http or https")),
+            Err(err) => self.error(
+                parameter,
+                format!("'{value}' is not a valid URL ({err}), expected something like https://example.com/path"),
+            ),
+        }
+    }
+
+    /// Checks that the value looks like `host:port`.
+    pub fn socket_address(&mut self, parameter: &'static str, value: &str) {
+        let valid = value
+            .rsplit_once(':')
+            .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+        if !valid {
+            self.error(
+                parameter,
+                format!("'{value}' is not a valid address, expected something like 0.0.0.0:8000"),
+            );
+        }
+    }
+
+    /// Checks that the path points to an existing, readable file.
+    pub fn readable_file(&mut self, parameter: &'static str, path: &Path) {
+        if let Err(err) = std::fs::File::open(path) {
+            self.error(
+                parameter,
+                format!("unable to read {} ({err})", path.display()),
+            );
+        }
+    }
+
+    /// Checks that the path points to an existing, readable directory.
+    pub fn readable_directory(&mut self, parameter: &'static str, path: &Path) {
+        if let Err(err) = std::fs::read_dir(path) {
+            self.error(
+                parameter,
+                format!("unable to read directory {} ({err})", path.display()),
+            );
+        }
+    }
+
+    /// Checks that the path points to an existing directory, or to a missing one that can be created in an existing parent.
+    pub fn directory(&mut self, parameter: &'static str, path: &Path) {
+        if path.is_dir() {
+            return;
+        }
+        if path.exists() {
+            self.error(parameter, format!("{} is not a directory", path.display()));
+        } else if !path
+            .parent()
+            .is_some_and(|parent| parent.as_os_str().is_empty() || parent.is_dir())
+        {
+            self.error(
+                parameter,
+                format!("neither {} nor its parent directory exist", path.display()),
+            );
+        }
+    }
+
+    /// Checks that the path is not a directory, and that its directory exists or can be created.
+    pub fn writable_file(&mut self, parameter: &'static str, path: &Path) {
+        if path.is_dir() {
+            self.error(parameter, format!("{} is a directory", path.display()));
+        } else if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            self.directory(parameter, parent);
+        }
+    }
+
+    pub fn finish(self) -> Result<(), ConfigErrors> {
+        if self.errors.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(self.errors)
+        }
+    }
+}
+
+impl Config {
+    /// Checks the syntax of every parameter and the consistency between them.
+    pub fn validate(&self) -> Result<(), ConfigErrors> {
+        let mut validator = Validator::default();
+
+        let base_url = validator.base_url("BASE_URL", &self.base_url);
+        validator.not_blank("GITHUB_APP_CLIENT_ID", &self.github_app_client_id);
+        validator.not_blank("GITHUB_APP_CLIENT_SECRET", &self.github_app_client_secret);
+        validator.base_url("GITHUB_WEB_URL", &self.github.web_url);
+        validator.base_url("GITHUB_API_URL", &self.github.api_url);
+        if let Some(keys_url) = &self.github.copilot_public_keys_url {
+            validator.url("GITHUB_COPILOT_PUBLIC_KEYS_URL", keys_url);
+        }
+        if let Some(copilot_api_url) = &self.github.copilot_api_url {
+            validator.base_url("GITHUB_COPILOT_API_URL", copilot_api_url);
+        }
+        validator.socket_address("BIND_ADDRESS", &self.bind_address);
+        if let Some(trusted_test_keys_file) = &self.trusted_test_keys_file {
+            if self.environment == Environment::Production {
+                validator.error(
+                    "TRUSTED_TEST_KEYS_FILE",
+                    "is not allowed in production, anyone holding the matching private keys could impersonate Copilot",
+                );
+            } else {
+                validator
+                    .readable_file("TRUSTED_TEST_KEYS_FILE", Path::new(trusted_test_keys_file));
+            }
+        }
+        if let Some(capture_path) = &self.capture_path {
+            validator.writable_file("CAPTURE_PATH", Path::new(capture_path));
+        }
+        if let Some(thread_store_path) = &self.thread_store_path {
+            validator.writable_file("THREAD_STORE_PATH", Path::new(thread_store_path));
+        }
+        if self.capture_max_bytes == 0 {
+            validator.error("CAPTURE_MAX_BYTES", "must be greater than 0");
+        }
+        if let Some(prompt_templates_dir) = &self.prompt_templates_dir {
+            validator.readable_directory("PROMPT_TEMPLATES_DIR", Path::new(prompt_templates_dir));
+        }
+        if self.prompt_max_tokens == 0 {
+            validator.error("PROMPT_MAX_TOKENS", "must be greater than 0");
+        }
+        match &self.metrics_token {
+            Some(metrics_token) => validator.not_blank("METRICS_TOKEN", metrics_token),
+            None if self.environment == Environment::Production => validator.error(
+                "METRICS_TOKEN",
+                "is required in production, `/metrics` is public without it",
+            ),
+            None => {}
+        }
+        if let Some(otlp_endpoint) = &self.otel_exporter_otlp_endpoint {
+            validator.base_url("OTEL_EXPORTER_OTLP_ENDPOINT", otlp_endpoint);
+        }
+        validator.not_blank("OTEL_SERVICE_NAME", &self.otel_service_name);
+        if !(0.0..=1.0).contains(&self.otel_sampling_ratio) {
+            validator.error("OTEL_SAMPLING_RATIO", "must be between 0 and 1");
+        }
+
+        match self.cookie_keys.as_deref() {
+            Some(cookie_keys) => {
+                if let Err(err) = CookieKeys::parse(cookie_keys) {
+                    validator.error("COOKIE_KEYS", format!("{err:#}"));
+                }
+            }
+            None if self.environment == Environment::Production => validator.error(
+                "COOKIE_KEYS",
+                "is required in production, generate one with `openssl rand -base64 64`",
+            ),
+            None => {}
+        }
+        if let Some(webhook_secret) = &self.github_webhook_secret {
+            validator.not_blank("GITHUB_WEBHOOK_SECRET", webhook_secret);
+        }
+
+        if self.environment == Environment::Production
+            && base_url.is_some_and(|url| url.scheme() != "https")
+        {
+            validator.error(
+                "BASE_URL",
+                "must use https in production, as OAuth cookies are only sent over secure connections",
+            );
+        }
+
+        validator.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Config, Environment};
+    use crate::config_validation::ConfigError;
+    use std::collections::HashMap;
+
+    fn config() -> Config {
+        Config::try_from(HashMap::from([
+            (
+                "BASE_URL".to_string(),
+                "https://toddler.example.com".to_string(),
+            ),
+            ("GITHUB_APP_CLIENT_ID".to_string(), "Iv1.abc".to_string()),
+            ("GITHUB_APP_CLIENT_SECRET".to_string(), "secret".to_string()),
+        ]))
+        .unwrap_or_else(|err| panic!("{err:#}"))
+    }
+
+    fn invalid_parameters(config: &Config) -> Vec<&'static str> {
+        config.validate().err().map_or_else(Vec::new, |errors| {
+            errors
+                .errors
+                .into_iter()
+                .map(|ConfigError { parameter, .. }| parameter)
+                .collect()
+        })
+    }
+
+    #[test]
+    fn valid_config() {
+        assert_eq!(config().validate(), Ok(()));
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let config = Config {
+            base_url: "localhost:8000/".to_string(),
+            github_app_client_secret: " ".to_string(),
+            cookie_keys: Some("not base64".to_string()),
+            bind_address: "8000".to_string(),
+            prompt_max_tokens: 0,
+            otel_sampling_ratio: 1.5,
+            ..config()
+        };
+        assert_eq!(
+            invalid_parameters(&config),
+            vec![
+                "BASE_URL",
+                "GITHUB_APP_CLIENT_SECRET",
+                "BIND_ADDRESS",
+                "PROMPT_MAX_TOKENS",
+                "OTEL_SAMPLING_RATIO",
+                "COOKIE_KEYS"
+            ]
+        );
+    }
+
+    #[test]
+    fn production_requires_cookie_keys_metrics_token_and_https() {
+        let config = Config {
+            base_url: "http://toddler.example.com".to_string(),
+            environment: Environment::Production,
+            ..config()
+        };
+        assert_eq!(
+            invalid_parameters(&config),
+            vec!["METRICS_TOKEN", "COOKIE_KEYS", "BASE_URL"]
+        );
+    }
+
+    #[test]
+    fn capture_path_must_be_a_file_in_an_existing_directory() {
+        let temp_dir = std::env::temp_dir().display().to_string();
+        let capture_in = |path: String| Config {
+            capture_path: Some(path),
+            ..config()
+        };
+        assert_eq!(
+            invalid_parameters(&capture_in(format!("{temp_dir}/requests.jsonl"))),
+            Vec::<&str>::new()
+        );
+        assert_eq!(
+            invalid_parameters(&capture_in(temp_dir.clone())),
+            vec!["CAPTURE_PATH"]
+        );
+        assert_eq!(
+            invalid_parameters(&capture_in(format!(
+                "{temp_dir}/toddler-missing/captures/requests.jsonl"
+            ))),
+            vec!["CAPTURE_PATH"]
+        );
+    }
+}
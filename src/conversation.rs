@@ -0,0 +1,213 @@
+use crate::messages::{ChatMessage, ChatRequest, CopilotReference, Role};
+use crate::slash_commands::strip_mention;
+use crate::thread_store::ThreadEntry;
+
+/// History of a request, cleaned from the artifacts of the Copilot protocol, to build prompts from.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Conversation<'a> {
+    /// Context added by Copilot in `_session` pseudo messages, such as the current time or the login of the user
+    pub session: Session<'a>,
+    /// User and assistant turns, oldest first
+    pub turns: Vec<Turn<'a>>,
+}
+
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Session<'a> {
+    pub context: Vec<&'a str>,
+    pub references: Vec<&'a CopilotReference>,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Turn<'a> {
+    pub role: TurnRole,
+    /// Content of the message, without the `@agent` mention
+    pub content: &'a str,
+    /// References sent with the message, or with the context-only messages preceding it
+    pub references: Vec<&'a CopilotReference>,
+    /// Answer recorded by the extension, with its tool results and metadata, for assistant turns
+    pub recorded: Option<&'a ThreadEntry>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TurnRole {
+    User,
+    Assistant,
+}
+
+impl ChatRequest {
+    /// Normalizes the messages of the request:
+    ///
+    /// * `_session` pseudo messages make the session context
+    /// * messages without content only carry references, attached to the next user turn, or to the last one
+    /// * assistant messages without content, whose answer was not recorded, are dropped
+    /// * messages of unknown roles are dropped
+    #[must_use]
+    pub fn conversation(&self) -> Conversation<'_> {
+        let mut conversation = Conversation::default();
+        let mut pending_references = vec![];
+        for message in &self.messages {
+            if is_session(message) {
+                conversation.session.context.push(message.content.trim());
+                conversation
+                    .session
+                    .references
+                    .extend(&message.copilot_references);
+                continue;
+            }
+            match message.role {
+                Role::User => {
+                    let content = strip_mention(&message.content, self.agent.as_deref());
+                    if content.is_empty() {
+                        pending_references.extend(&message.copilot_references);
+                    } else {
+                        let mut references = std::mem::take(&mut pending_references);
+                        references.extend(&message.copilot_references);
+                        conversation.turns.push(Turn {
+                            role: TurnRole::User,
+                            content,
+                            references,
+                            recorded: None,
+                        });
+                    }
+                }
+                Role::Assistant if !message.content.trim().is_empty() => {
+                    conversation.turns.push(Turn {
+                        role: TurnRole::Assistant,
+                        content: message.content.trim(),
+                        references: message.copilot_references.iter().collect(),
+                        recorded: message.recorded.as_ref(),
+                    });
+                }
+                Role::Assistant | Role::Unknown(_) => {}
+            }
+        }
+        if !pending_references.is_empty() {
+            match conversation
+                .turns
+                .iter_mut()
+                .rev()
+                .find(|turn| turn.role == TurnRole::User)
+            {
+                Some(turn) => turn.references.extend(pending_references),
+                None => conversation.session.references.extend(pending_references),
+            }
+        }
+        conversation
+    }
+}
+
+fn is_session(message: &ChatMessage) -> bool {
+    message.name.as_deref() == Some("_session")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conversation::TurnRole;
+    use crate::messages::{ChatMessage, ChatRequest, CopilotReference, Role};
+    use std::fs;
+
+    fn sample(name: &str) -> anyhow::Result<ChatRequest> {
+        ChatRequest::parse(&fs::read_to_string(format!("samples/{name}.json"))?)
+    }
+
+    fn reference_types(references: &[&CopilotReference]) -> Vec<String> {
+        references
+            .iter()
+            .map(|reference| {
+                serde_json::to_value(reference).map_or_else(
+                    |_| String::new(),
+                    |value| value["type"].as_str().unwrap_or_default().to_string(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn empty_assistant_turns_are_dropped() -> anyhow::Result<()> {
+        let request = sample("chat_request_from_vs_code_with_selection")?;
+        let conversation = request.conversation();
+        let turns: Vec<_> = conversation
+            .turns
+            .iter()
+            .map(|turn| (turn.role, turn.content))
+            .collect();
+        assert_eq!(
+            turns,
+            vec![
+                (TurnRole::User, "help with this file"),
+                (TurnRole::User, "because")
+            ]
+        );
+        assert_eq!(
+            reference_types(&conversation.turns[1].references),
+            vec!["client.selection", "github.repository"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn session_and_context_only_messages_are_set_apart() -> anyhow::Result<()> {
+        let request = sample("chat_request_from_web_with_repository")?;
+        let conversation = request.conversation();
+        assert_eq!(conversation.session.context.len(), 1);
+        assert!(conversation.session.context[0].starts_with("Current Date and Time (UTC)"));
+        assert_eq!(
+            reference_types(&conversation.session.references),
+            vec!["github.current-url"]
+        );
+        assert_eq!(conversation.turns.len(), 2);
+        assert_eq!(conversation.turns[0].content, "coucou");
+        assert_eq!(
+            reference_types(&conversation.turns[0].references),
+            vec!["github.repository"]
+        );
+        // the repository is sent again in a context-only message before the last user message
+        assert_eq!(conversation.turns[1].content, "tuttut");
+        assert_eq!(
+            reference_types(&conversation.turns[1].references),
+            vec!["github.repository"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn only_the_mention_of_the_agent_is_stripped() -> anyhow::Result<()> {
+        let mut request = sample("chat_request_from_vs_code")?;
+        request.messages[0].content = "@mais-arreeeeeeeeteuuuu what is it?".to_string();
+        assert_eq!(request.conversation().turns[0].content, "what is it?");
+        request.messages[0].content = "@Override why is it needed?".to_string();
+        assert_eq!(
+            request.conversation().turns[0].content,
+            "@Override why is it needed?"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn trailing_references_are_attached_to_the_last_user_turn() -> anyhow::Result<()> {
+        let mut request = sample("chat_request_from_vs_code")?;
+        let references = std::mem::take(&mut request.messages[0].copilot_references);
+        request.messages.push(ChatMessage {
+            role: Role::Assistant,
+            content: "It is a Svelte app.".to_string(),
+            name: None,
+            copilot_references: vec![],
+            recorded: None,
+        });
+        request.messages.push(ChatMessage {
+            role: Role::User,
+            content: "@mais-arreeeeeeeeteuuuu".to_string(),
+            name: None,
+            copilot_references: references,
+            recorded: None,
+        });
+        let conversation = request.conversation();
+        assert_eq!(conversation.turns.len(), 2);
+        assert_eq!(conversation.turns[1].role, TurnRole::Assistant);
+        assert_eq!(
+            reference_types(&conversation.turns[0].references),
+            vec!["github.repository"]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+use crate::config::{Config, Environment};
+use anyhow::{anyhow, Context};
+use axum::http::HeaderMap;
+use axum_extra::extract::cookie::{Cookie, Key};
+use axum_extra::extract::PrivateCookieJar;
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use tracing::warn;
+
+/// Keys used to encrypt private cookies.
+///
+/// The first key is the current one, used to encrypt new cookies.
+/// The following ones are older keys, only used to decrypt cookies issued before a rotation.
+#[derive(Clone)]
+pub struct CookieKeys {
+    keys: Vec<Key>,
+}
+
+impl CookieKeys {
+    pub fn from_config(config: &Config) -> anyhow::Result<Self> {
+        match config.cookie_keys.as_deref() {
+            // a blank value is rejected, as by `Config::validate`, rather than silently replaced by a random key
+            Some(raw_keys) => Self::parse(raw_keys),
+            None if config.environment == Environment::Production => Err(anyhow!(
+                "[config] COOKIE_KEYS is required in production, generate one with `openssl rand -base64 64`"
+            )),
+            None => {
+                warn!("[config] No COOKIE_KEYS configured, using a random key: cookies will not survive a restart");
+                Ok(Self {
+                    keys: vec![Key::generate()],
+                })
+            }
+        }
+    }
+
+    /// Parses a comma-separated list of base64 encoded keys, newest first.
+    pub fn parse(raw_keys: &str) -> anyhow::Result<Self> {
+        let keys = raw_keys
+            .split(',')
+            .map(str::trim)
+            .filter(|raw_key| !raw_key.is_empty())
+            .enumerate()
+            .map(|(index, raw_key)| {
+                let bytes = BASE64_STANDARD.decode(raw_key).with_context(|| {
+                    format!("[config] COOKIE_KEYS #{index} is not valid base64")
+                })?;
+                Key::try_from(bytes.as_slice()).with_context(|| {
+                    format!("[config] COOKIE_KEYS #{index} must be at least 64 bytes long")
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if keys.is_empty() {
+            return Err(anyhow!("[config] COOKIE_KEYS does not contain any key"));
+        }
+        Ok(Self { keys })
+    }
+
+    #[must_use]
+    pub fn current(&self) -> &Key {
+        &self.keys[0]
+    }
+
+    /// Reads a private cookie, trying the current key first, then the older ones.
+    #[must_use]
+    pub fn get(
+        &self,
+        jar: &PrivateCookieJar,
+        headers: &HeaderMap,
+        name: &str,
+    ) -> Option<Cookie<'static>> {
+        jar.get(name).or_else(|| {
+            self.keys[1..]
+                .iter()
+                .find_map(|key| PrivateCookieJar::from_headers(headers, key.clone()).get(name))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::cookie_keys::CookieKeys;
+    use axum::http::header::{COOKIE, SET_COOKIE};
+    use axum::http::{HeaderMap, HeaderValue};
+    use axum::response::IntoResponse;
+    use axum_extra::extract::cookie::Cookie;
+    use axum_extra::extract::PrivateCookieJar;
+    use base64::prelude::BASE64_STANDARD;
+    use base64::Engine;
+
+    fn raw_key(seed: u8) -> String {
+        BASE64_STANDARD.encode([seed; 64])
+    }
+
+    fn headers_with_cookie_encrypted_by(
+        keys: &CookieKeys,
+        name: &'static str,
+        value: &'static str,
+    ) -> anyhow::Result<HeaderMap> {
+        let response = PrivateCookieJar::new(keys.current().clone())
+            .add(Cookie::new(name, value))
+            .into_response();
+        let set_cookie = Cookie::parse(response.headers()[SET_COOKIE].to_str()?.to_string())?;
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            COOKIE,
+            HeaderValue::from_str(&format!("{}={}", set_cookie.name(), set_cookie.value()))?,
+        );
+        Ok(headers)
+    }
+
+    #[test]
+    fn parse_rejects_short_keys() {
+        assert!(CookieKeys::parse(&BASE64_STANDARD.encode([1u8; 16])).is_err());
+        assert!(CookieKeys::parse(" , ").is_err());
+    }
+
+    #[test]
+    fn blank_config_is_rejected_as_by_validation() -> anyhow::Result<()> {
+        let config = Config::from_vars([
+            ("BASE_URL".to_string(), "http://localhost:8000".to_string()),
+            ("GITHUB_APP_CLIENT_ID".to_string(), "Iv1.abc".to_string()),
+            ("GITHUB_APP_CLIENT_SECRET".to_string(), "secret".to_string()),
+            ("COOKIE_KEYS".to_string(), String::new()),
+        ])?;
+        assert!(CookieKeys::from_config(&config).is_err());
+        assert!(config.validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn get_accepts_cookies_encrypted_with_older_keys() -> anyhow::Result<()> {
+        let old_keys = CookieKeys::parse(&raw_key(1))?;
+        let rotated_keys = CookieKeys::parse(&format!("{},{}", raw_key(2), raw_key(1)))?;
+        let headers = headers_with_cookie_encrypted_by(&old_keys, "gh_state", "secret")?;
+
+        let jar = PrivateCookieJar::from_headers(&headers, rotated_keys.current().clone());
+        assert!(jar.get("gh_state").is_none());
+        assert_eq!(
+            rotated_keys
+                .get(&jar, &headers, "gh_state")
+                .map(|c| c.value().to_string()),
+            Some("secret".to_string())
+        );
+
+        let unrelated_keys = CookieKeys::parse(&raw_key(3))?;
+        let jar = PrivateCookieJar::from_headers(&headers, unrelated_keys.current().clone());
+        assert!(unrelated_keys.get(&jar, &headers, "gh_state").is_none());
+        Ok(())
+    }
+}
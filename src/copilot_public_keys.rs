@@ -1,3 +1,4 @@
+use crate::metrics::Metrics;
 use anyhow::{anyhow, Context};
 use axum::http::header::USER_AGENT;
 use base64::prelude::BASE64_STANDARD;
@@ -6,29 +7,46 @@ use ecdsa::der::Signature;
 use ecdsa::VerifyingKey;
 use p256::NistP256;
 use signature::Verifier;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{info, instrument, warn, Span};
 
-#[derive(serde::Deserialize, Clone, Debug)]
+/// Minimum delay between two refreshes triggered by unknown key identifiers,
+/// so that forged identifiers cannot be used to hammer the keys endpoint.
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
 pub struct CopilotPublicKeys {
     pub public_keys: Vec<CopilotPublicKey>,
 }
 
-#[derive(serde::Deserialize, Clone, Debug)]
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
 pub struct CopilotPublicKey {
     pub key_identifier: String,
     pub key: String,
     pub is_current: bool,
 }
 
-pub async fn load_copilot_public_key(url: &str) -> anyhow::Result<VerifyingKey<NistP256>> {
-    let client = reqwest::Client::new();
-    let keys: CopilotPublicKeys = client
+async fn fetch_copilot_public_keys(
+    client: &reqwest::Client,
+    url: &str,
+) -> anyhow::Result<CopilotPublicKeys> {
+    Ok(client
         .get(url)
         .header(USER_AGENT, "My Rust Program 1.0")
         .send()
         .await?
+        .error_for_status()?
         .json()
-        .await?;
+        .await?)
+}
+
+pub async fn load_copilot_public_key(url: &str) -> anyhow::Result<VerifyingKey<NistP256>> {
+    let keys = fetch_copilot_public_keys(&reqwest::Client::new(), url).await?;
     let optional_raw_key = keys
         .public_keys
         .into_iter()
@@ -41,6 +59,196 @@ pub async fn load_copilot_public_key(url: &str) -> anyhow::Result<VerifyingKey<N
     )
 }
 
+/// Copilot public keys by identifier, refreshed when a request is signed with an unknown one.
+#[derive(Clone)]
+pub struct CopilotKeyStore {
+    url: String,
+    http: reqwest::Client,
+    keys: Arc<RwLock<KeySet>>,
+    /// Keys trusted on top of the published ones, to replay requests signed locally
+    trusted_keys: Arc<HashMap<String, VerifyingKey<NistP256>>>,
+    metrics: Metrics,
+    /// Last refresh triggered by an unknown key identifier
+    last_forced_refresh: Arc<Mutex<Option<Instant>>>,
+}
+
+#[derive(Default)]
+struct KeySet {
+    by_identifier: HashMap<String, VerifyingKey<NistP256>>,
+    current: Option<String>,
+}
+
+impl CopilotKeyStore {
+    /// Loads the keys published at the given URL, failing if none of them can be used.
+    pub async fn load(url: &str, metrics: Metrics) -> anyhow::Result<Self> {
+        let store = Self {
+            url: url.to_string(),
+            http: reqwest::Client::new(),
+            keys: Arc::default(),
+            trusted_keys: Arc::default(),
+            metrics,
+            last_forced_refresh: Arc::default(),
+        };
+        store.refresh().await?;
+        Ok(store)
+    }
+
+    /// Trusts the keys of a file in the format of the published ones, whatever their `is_current` flag.
+    pub fn with_trusted_keys_file(mut self, path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("[config] Unable to read {}", path.display()))?;
+        let keys: CopilotPublicKeys = serde_json::from_str(&content)
+            .with_context(|| format!("[config] Unable to parse {}", path.display()))?;
+        let trusted_keys = keys
+            .public_keys
+            .into_iter()
+            .map(|key| Ok((key.key_identifier, parse_key(&key.key)?)))
+            .collect::<anyhow::Result<HashMap<_, _>>>()
+            .with_context(|| format!("[config] Invalid key in {}", path.display()))?;
+        warn!(
+            path = %path.display(),
+            keys = trusted_keys.len(),
+            "[copilot] Trusting test keys, requests signed with them are accepted"
+        );
+        self.trusted_keys = Arc::new(trusted_keys);
+        Ok(self)
+    }
+
+    /// Fetches the published keys again, replacing the known ones.
+    #[instrument(name = "copilot_keys_refresh", skip_all, fields(url = %self.url, keys))]
+    pub async fn refresh(&self) -> anyhow::Result<()> {
+        let refreshed = self.replace_keys().await;
+        self.metrics.key_refresh(refreshed.is_ok());
+        refreshed
+    }
+
+    async fn replace_keys(&self) -> anyhow::Result<()> {
+        let published = fetch_copilot_public_keys(&self.http, &self.url).await?;
+        let mut keys = KeySet::default();
+        for key in published.public_keys {
+            match parse_key(&key.key) {
+                Ok(verifying_key) => {
+                    if key.is_current {
+                        keys.current = Some(key.key_identifier.clone());
+                    }
+                    keys.by_identifier.insert(key.key_identifier, verifying_key);
+                }
+                Err(err) => {
+                    warn!(error = ?err, key_identifier = key.key_identifier, "[copilot] Ignoring unparseable public key");
+                }
+            }
+        }
+        if keys.current.is_none() {
+            return Err(anyhow!("No current public keys in: {}", self.url));
+        }
+        Span::current().record("keys", keys.by_identifier.len());
+        info!("[copilot] Public keys refreshed");
+        *self
+            .keys
+            .write()
+            .unwrap_or_else(std::sync::PoisonError::into_inner) = keys;
+        Ok(())
+    }
+
+    /// Verifies the signature of a request body with the given key, or the current one when the request does not tell.
+    ///
+    /// An unknown key identifier triggers a refresh of the keys, at most once per minute.
+    #[instrument(name = "signature_verification", skip_all, fields(key_identifier))]
+    pub async fn verify(
+        &self,
+        key_identifier: Option<&str>,
+        signature: &str,
+        body: &str,
+    ) -> Result<(), SignatureError> {
+        Span::current().record("key_identifier", key_identifier);
+        let decoded_signature = BASE64_STANDARD
+            .decode(signature)
+            .context("Error while b64 decoding signature")
+            .map_err(SignatureError::Malformed)?;
+        let signature = Signature::<NistP256>::from_bytes(&decoded_signature)
+            .context("Error while parsing signature")
+            .map_err(SignatureError::Malformed)?;
+        let key = if let Some(key) = self.key(key_identifier) {
+            key
+        } else {
+            if self.claim_refresh() {
+                self.refresh()
+                    .await
+                    .map_err(SignatureError::KeysUnavailable)?;
+            }
+            self.key(key_identifier).ok_or_else(|| {
+                SignatureError::UnknownKey(key_identifier.unwrap_or_default().to_string())
+            })?
+        };
+        key.verify(body.as_bytes(), &signature)
+            .map_err(|_| SignatureError::Mismatch)
+    }
+
+    fn key(&self, key_identifier: Option<&str>) -> Option<VerifyingKey<NistP256>> {
+        let keys = self
+            .keys
+            .read()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        let key_identifier = key_identifier.or(keys.current.as_deref())?;
+        keys.by_identifier
+            .get(key_identifier)
+            .or_else(|| self.trusted_keys.get(key_identifier))
+            .copied()
+    }
+
+    /// Tells if a refresh may happen now, recording it if so.
+    fn claim_refresh(&self) -> bool {
+        let mut last_forced_refresh = self
+            .last_forced_refresh
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        if last_forced_refresh.is_some_and(|instant| instant.elapsed() < MIN_REFRESH_INTERVAL) {
+            return false;
+        }
+        *last_forced_refresh = Some(Instant::now());
+        true
+    }
+}
+
+/// Why the signature of a request was rejected.
+#[derive(Debug)]
+pub enum SignatureError {
+    /// The signature is not a base64 encoded DER signature
+    Malformed(anyhow::Error),
+    /// The request claims to be signed with a key that is not published
+    UnknownKey(String),
+    /// The published keys could not be downloaded again
+    KeysUnavailable(anyhow::Error),
+    /// The signature does not match the body
+    Mismatch,
+}
+
+impl SignatureError {
+    /// Short name of the failure, used as a metric label.
+    #[must_use]
+    pub const fn reason(&self) -> &'static str {
+        match self {
+            Self::Malformed(_) => "malformed",
+            Self::UnknownKey(_) => "unknown_key",
+            Self::KeysUnavailable(_) => "keys_unavailable",
+            Self::Mismatch => "mismatch",
+        }
+    }
+}
+
+impl Display for SignatureError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Malformed(err) => write!(f, "Malformed signature: {err:#}"),
+            Self::UnknownKey(key_identifier) => write!(f, "Unknown public key: {key_identifier}"),
+            Self::KeysUnavailable(err) => write!(f, "Unable to refresh public keys: {err:#}"),
+            Self::Mismatch => write!(f, "Signature does not match body"),
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
 pub trait VerifyFromStr {
     fn verify_from_str(&self, sig: &str, content: &str) -> anyhow::Result<()>;
 }
@@ -64,7 +272,93 @@ fn parse_key(raw_key: &str) -> anyhow::Result<VerifyingKey<NistP256>> {
 
 #[cfg(test)]
 mod tests {
-    use crate::copilot_public_keys::{load_copilot_public_key, VerifyFromStr};
+    use crate::copilot_public_keys::{
+        load_copilot_public_key, CopilotKeyStore, SignatureError, VerifyFromStr,
+    };
+    use crate::metrics::Metrics;
+    use axum::extract::State;
+    use axum::routing::get;
+    use axum::{Json, Router};
+    use base64::prelude::BASE64_STANDARD;
+    use base64::Engine;
+    use p256::ecdsa::{DerSignature, SigningKey};
+    use p256::pkcs8::{EncodePublicKey, LineEnding};
+    use signature::Signer;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    fn signing_key(seed: u8) -> anyhow::Result<SigningKey> {
+        Ok(SigningKey::from_slice(&[seed; 32])?)
+    }
+
+    fn published_key(
+        key_identifier: &str,
+        signing_key: &SigningKey,
+        is_current: bool,
+    ) -> anyhow::Result<serde_json::Value> {
+        Ok(serde_json::json!({
+            "key_identifier": key_identifier,
+            "key": signing_key.verifying_key().to_public_key_pem(LineEnding::LF)?,
+            "is_current": is_current,
+        }))
+    }
+
+    fn sign(signing_key: &SigningKey, body: &str) -> String {
+        let signature: DerSignature = signing_key.sign(body.as_bytes());
+        BASE64_STANDARD.encode(signature.as_bytes())
+    }
+
+    #[tokio::test]
+    async fn unknown_key_identifier_triggers_a_refresh() -> anyhow::Result<()> {
+        let (old_key, new_key) = (signing_key(1)?, signing_key(2)?);
+        let before_rotation =
+            serde_json::json!({ "public_keys": [published_key("old", &old_key, true)?] });
+        let after_rotation = serde_json::json!({ "public_keys": [
+            published_key("old", &old_key, false)?,
+            published_key("new", &new_key, true)?,
+        ] });
+        let rotated = Arc::new(AtomicBool::new(false));
+        let router = Router::new()
+            .route(
+                "/keys",
+                get(|State(rotated): State<Arc<AtomicBool>>| async move {
+                    Json(if rotated.load(Ordering::SeqCst) {
+                        after_rotation
+                    } else {
+                        before_rotation
+                    })
+                }),
+            )
+            .with_state(rotated.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/keys", listener.local_addr()?);
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let store = CopilotKeyStore::load(&url, Metrics::default()).await?;
+        let body = r#"{"messages":[]}"#;
+        store.verify(None, &sign(&old_key, body), body).await?;
+        store
+            .verify(Some("old"), &sign(&old_key, body), body)
+            .await?;
+        assert!(matches!(
+            store.verify(Some("old"), &sign(&new_key, body), body).await,
+            Err(SignatureError::Mismatch)
+        ));
+
+        rotated.store(true, Ordering::SeqCst);
+        store
+            .verify(Some("new"), &sign(&new_key, body), body)
+            .await?;
+        store.verify(None, &sign(&new_key, body), body).await?;
+        // refreshes are rate limited
+        assert!(matches!(
+            store
+                .verify(Some("forged"), &sign(&new_key, body), body)
+                .await,
+            Err(SignatureError::UnknownKey(_))
+        ));
+        Ok(())
+    }
 
     #[tokio::test]
     async fn load_pub_key() -> anyhow::Result<()> {
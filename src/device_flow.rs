@@ -0,0 +1,444 @@
+use crate::oauth::store_token;
+use crate::state::AppState;
+use axum::extract::{Path, State};
+use axum::http::header::ACCEPT;
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use oauth2::basic::BasicTokenResponse;
+use oauth2::reqwest::async_http_client;
+use oauth2::{CsrfToken, Scope, StandardDeviceAuthorizationResponse};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use time::{Duration, OffsetDateTime};
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+/// How long the outcome of a finished flow is kept for the client to poll it.
+const FINISHED_FLOW_RETENTION: Duration = Duration::minutes(10);
+/// Flows polling GitHub at the same time, each one lasting as long as its device code.
+const MAX_PENDING_FLOWS: usize = 100;
+/// Flows polling GitHub at the same time for a client, identified by the last address of `X-Forwarded-For`,
+/// requests without it sharing the same limit.
+const MAX_PENDING_FLOWS_PER_CLIENT: usize = 3;
+/// Added to the polling interval when GitHub answers `slow_down` without a new interval.
+const SLOW_DOWN_INCREMENT: std::time::Duration = std::time::Duration::from_secs(5);
+const DEVICE_CODE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+/// Device flows in progress, keyed by an opaque flow id handed to the client.
+#[derive(Clone, Default)]
+pub struct DeviceFlows {
+    flows: Arc<RwLock<HashMap<String, DeviceFlow>>>,
+}
+
+#[derive(Clone, Debug)]
+struct DeviceFlow {
+    status: DeviceFlowStatus,
+    client: String,
+    updated_at: OffsetDateTime,
+}
+
+#[derive(serde::Serialize, Clone, Debug, Eq, PartialEq)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum DeviceFlowStatus {
+    Pending,
+    Complete { login: String },
+    Failed { error: String },
+}
+
+impl DeviceFlows {
+    /// Registers a pending flow, `None` when too many are pending, overall or for the client.
+    fn start(&self, client: &str) -> Option<String> {
+        let mut flows = self.flows.write().ok()?;
+        let pending: Vec<&DeviceFlow> = flows
+            .values()
+            .filter(|flow| flow.status == DeviceFlowStatus::Pending)
+            .collect();
+        let pending_for_client = pending.iter().filter(|flow| flow.client == client).count();
+        if pending.len() >= MAX_PENDING_FLOWS || pending_for_client >= MAX_PENDING_FLOWS_PER_CLIENT
+        {
+            return None;
+        }
+        let flow_id = CsrfToken::new_random().secret().clone();
+        flows.insert(
+            flow_id.clone(),
+            DeviceFlow {
+                status: DeviceFlowStatus::Pending,
+                client: client.to_string(),
+                updated_at: OffsetDateTime::now_utc(),
+            },
+        );
+        Some(flow_id)
+    }
+
+    fn set(&self, flow_id: &str, status: DeviceFlowStatus) {
+        if let Ok(mut flows) = self.flows.write() {
+            flows.retain(|_, flow| {
+                flow.status == DeviceFlowStatus::Pending
+                    || OffsetDateTime::now_utc() - flow.updated_at < FINISHED_FLOW_RETENTION
+            });
+            if let Some(flow) = flows.get_mut(flow_id) {
+                flow.status = status;
+                flow.updated_at = OffsetDateTime::now_utc();
+            }
+        }
+    }
+
+    /// Forgets a flow which could not start.
+    fn forget(&self, flow_id: &str) {
+        if let Ok(mut flows) = self.flows.write() {
+            flows.remove(flow_id);
+        }
+    }
+
+    /// Returns the status of the given flow, forgetting it once finished.
+    fn poll(&self, flow_id: &str) -> Option<DeviceFlowStatus> {
+        let mut flows = self.flows.write().ok()?;
+        let status = flows.get(flow_id)?.status.clone();
+        if status != DeviceFlowStatus::Pending {
+            flows.remove(flow_id);
+        }
+        Some(status)
+    }
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct DeviceFlowStart {
+    flow_id: String,
+    user_code: String,
+    verification_uri: String,
+    expires_in: u64,
+    interval: u64,
+    message: String,
+}
+
+/// Starts a device authorization flow, the client is expected to show the user code then poll for completion.
+///
+/// As each flow polls GitHub until its device code expires, flows are answered with `429 Too Many Requests`
+/// past [`MAX_PENDING_FLOWS`], or [`MAX_PENDING_FLOWS_PER_CLIENT`] for the same client.
+pub async fn start_device_flow(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<DeviceFlowStart>, StatusCode> {
+    let client = client_address(&headers);
+    let Some(flow_id) = state.device_flows.start(&client) else {
+        warn!(client, "[http] start_device_flow: Too many pending flows");
+        return Err(StatusCode::TOO_MANY_REQUESTS);
+    };
+    let details = match request_device_code(&state).await {
+        Ok(details) => details,
+        Err(status) => {
+            state.device_flows.forget(&flow_id);
+            return Err(status);
+        }
+    };
+
+    let user_code = details.user_code().secret().clone();
+    let verification_uri = details.verification_uri().to_string();
+    let start = DeviceFlowStart {
+        message: format!("Open {verification_uri} and enter the code {user_code}"),
+        flow_id: flow_id.clone(),
+        user_code,
+        verification_uri,
+        expires_in: details.expires_in().as_secs(),
+        interval: details.interval().as_secs(),
+    };
+
+    tokio::spawn(async move {
+        let status = match wait_for_token(&state, &details).await {
+            Ok(token) => match store_token(&state, &token).await {
+                Ok(user) => DeviceFlowStatus::Complete { login: user.login },
+                Err(err) => {
+                    error!(error = ?err, "[http] device_flow: Unable to identify the user");
+                    DeviceFlowStatus::Failed {
+                        error: "unable to identify the GitHub account".to_string(),
+                    }
+                }
+            },
+            Err(error) => DeviceFlowStatus::Failed { error },
+        };
+        info!(?status, "[http] device_flow: Finished");
+        state.device_flows.set(&flow_id, status);
+    });
+
+    Ok(Json(start))
+}
+
+async fn request_device_code(
+    state: &AppState,
+) -> Result<StandardDeviceAuthorizationResponse, StatusCode> {
+    state
+        .oauth_gh_client
+        .exchange_device_code()
+        .map_err(|err| {
+            error!(error = ?err, "[http] start_device_flow: Device flow not configured");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .add_scope(Scope::new("public_repo".to_string()))
+        .add_scope(Scope::new("user:email".to_string()))
+        .request_async(async_http_client)
+        .await
+        .map_err(|err| {
+            error!(error = ?err, "[http] start_device_flow: Unable to request a device code");
+            StatusCode::BAD_GATEWAY
+        })
+}
+
+/// Last address of `X-Forwarded-For`, appended by the proxy in front of the app,
+/// the previous ones being sent by the caller. Requests without it are all counted as the same client.
+fn client_address(headers: &HeaderMap) -> String {
+    headers
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit(',').next())
+        .map(str::trim)
+        .filter(|address| !address.is_empty())
+        .unwrap_or("unknown")
+        .to_string()
+}
+
+async fn wait_for_token(
+    state: &AppState,
+    details: &StandardDeviceAuthorizationResponse,
+) -> Result<BasicTokenResponse, String> {
+    let token_res = poll_device_token(
+        &reqwest::Client::new(),
+        &state.config.github.token_url(),
+        &state.config.github_app_client_id,
+        details.device_code().secret(),
+        details.interval(),
+        details.expires_in(),
+    )
+    .instrument(info_span!("oauth_exchange", grant = "device_code"))
+    .await;
+    state
+        .metrics
+        .oauth_exchange("device_code", token_res.is_ok());
+    token_res.inspect_err(|err| {
+        error!(
+            error = err,
+            "[http] device_flow: Unable to exchange device code"
+        );
+    })
+}
+
+/// Answer of the token endpoint to a device code, GitHub answering errors with `200 OK` as well.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum DeviceTokenAnswer {
+    Error {
+        error: String,
+        #[serde(default)]
+        error_description: Option<String>,
+        /// New polling interval, in seconds, sent with `slow_down`
+        #[serde(default)]
+        interval: Option<u64>,
+    },
+    Token(BasicTokenResponse),
+}
+
+/// Polls the token endpoint until the user authorizes the device, denies it, or the device code expires,
+/// waiting the interval asked by GitHub between polls.
+async fn poll_device_token(
+    http: &reqwest::Client,
+    token_url: &str,
+    client_id: &str,
+    device_code: &str,
+    mut interval: std::time::Duration,
+    expires_in: std::time::Duration,
+) -> Result<BasicTokenResponse, String> {
+    let expires_at = tokio::time::Instant::now() + expires_in;
+    loop {
+        tokio::time::sleep(interval).await;
+        if tokio::time::Instant::now() >= expires_at {
+            return Err("the device code expired".to_string());
+        }
+        let response = http
+            .post(token_url)
+            .header(ACCEPT, "application/json")
+            .form(&[
+                ("client_id", client_id),
+                ("device_code", device_code),
+                ("grant_type", DEVICE_CODE_GRANT_TYPE),
+            ])
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|err| format!("unable to reach the token endpoint ({err})"))?;
+        let answer: DeviceTokenAnswer = response
+            .json()
+            .await
+            .map_err(|err| format!("unexpected answer of the token endpoint ({err})"))?;
+        match answer {
+            DeviceTokenAnswer::Token(token) => return Ok(token),
+            DeviceTokenAnswer::Error { error, .. } if error == "authorization_pending" => {
+                debug!("[http] device_flow: Authorization pending");
+            }
+            DeviceTokenAnswer::Error {
+                error,
+                interval: new_interval,
+                ..
+            } if error == "slow_down" => {
+                interval = new_interval.map_or(
+                    interval + SLOW_DOWN_INCREMENT,
+                    std::time::Duration::from_secs,
+                );
+                debug!(?interval, "[http] device_flow: Slowing down");
+            }
+            DeviceTokenAnswer::Error { error, .. } if error == "expired_token" => {
+                return Err("the device code expired".to_string());
+            }
+            DeviceTokenAnswer::Error { error, .. } if error == "access_denied" => {
+                return Err("the authorization was denied".to_string());
+            }
+            DeviceTokenAnswer::Error {
+                error,
+                error_description,
+                ..
+            } => return Err(error_description.unwrap_or(error)),
+        }
+    }
+}
+
+#[allow(clippy::unused_async)]
+pub async fn poll_device_flow(
+    State(state): State<AppState>,
+    Path(flow_id): Path<String>,
+) -> Result<Json<DeviceFlowStatus>, StatusCode> {
+    state
+        .device_flows
+        .poll(&flow_id)
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::device_flow::{
+        client_address, poll_device_token, DeviceFlowStatus, DeviceFlows, MAX_PENDING_FLOWS,
+        MAX_PENDING_FLOWS_PER_CLIENT,
+    };
+    use axum::extract::State;
+    use axum::http::{HeaderMap, HeaderValue};
+    use axum::routing::post;
+    use axum::{Form, Json, Router};
+    use oauth2::TokenResponse;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// Answers as GitHub does, with `200 OK` whatever the outcome, the device code telling the scenario.
+    async fn mock_token_endpoint(
+        State(polls): State<Arc<AtomicUsize>>,
+        Form(form): Form<HashMap<String, String>>,
+    ) -> Json<serde_json::Value> {
+        let poll = polls.fetch_add(1, Ordering::SeqCst);
+        let answer = match (form["device_code"].as_str(), poll) {
+            (_, _) if form["grant_type"] != "urn:ietf:params:oauth:grant-type:device_code" => {
+                serde_json::json!({ "error": "unsupported_grant_type" })
+            }
+            ("authorized", 0) => serde_json::json!({ "error": "authorization_pending" }),
+            ("authorized", 1) => serde_json::json!({ "error": "slow_down", "interval": 0 }),
+            ("authorized", _) => serde_json::json!({
+                "access_token": "ghu_token", "token_type": "bearer", "scope": "public_repo,user:email"
+            }),
+            ("denied", _) => serde_json::json!({ "error": "access_denied" }),
+            _ => serde_json::json!({ "error": "expired_token" }),
+        };
+        Json(answer)
+    }
+
+    async fn poll_mock(device_code: &str) -> anyhow::Result<(Result<String, String>, usize)> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let token_url = format!("http://{}/login/oauth/access_token", listener.local_addr()?);
+        let polls = Arc::new(AtomicUsize::new(0));
+        let router = Router::new()
+            .route("/login/oauth/access_token", post(mock_token_endpoint))
+            .with_state(polls.clone());
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let token = poll_device_token(
+            &reqwest::Client::new(),
+            &token_url,
+            "client-id",
+            device_code,
+            Duration::from_millis(10),
+            Duration::from_secs(10),
+        )
+        .await
+        .map(|token| token.access_token().secret().clone());
+        Ok((token, polls.load(Ordering::SeqCst)))
+    }
+
+    #[tokio::test]
+    async fn device_token_is_polled_while_authorization_is_pending() -> anyhow::Result<()> {
+        assert_eq!(
+            poll_mock("authorized").await?,
+            (Ok("ghu_token".to_string()), 3)
+        );
+        assert_eq!(
+            poll_mock("denied").await?,
+            (Err("the authorization was denied".to_string()), 1)
+        );
+        assert_eq!(
+            poll_mock("expired").await?,
+            (Err("the device code expired".to_string()), 1)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn pending_flows_are_capped() {
+        let flows = DeviceFlows::default();
+        for _ in 0..MAX_PENDING_FLOWS_PER_CLIENT {
+            assert!(flows.start("203.0.113.7").is_some());
+        }
+        assert_eq!(flows.start("203.0.113.7"), None);
+        let mut started = MAX_PENDING_FLOWS_PER_CLIENT;
+        while started < MAX_PENDING_FLOWS {
+            assert!(flows.start(&format!("198.51.100.{started}")).is_some());
+            started += 1;
+        }
+        assert_eq!(flows.start("192.0.2.1"), None);
+    }
+
+    #[test]
+    fn clients_are_identified_by_the_address_appended_by_the_proxy() {
+        let headers = |forwarded_for: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-forwarded-for", HeaderValue::from_static(forwarded_for));
+            headers
+        };
+        assert_eq!(client_address(&headers("203.0.113.7")), "203.0.113.7");
+        // addresses sent by the caller are ignored
+        assert_eq!(
+            client_address(&headers("10.0.0.1, 192.0.2.1, 203.0.113.7")),
+            "203.0.113.7"
+        );
+        // requests without the header share the same limit
+        assert_eq!(client_address(&HeaderMap::new()), "unknown");
+        assert_eq!(client_address(&headers(" ")), "unknown");
+    }
+
+    #[test]
+    fn finished_flows_are_forgotten_once_polled() {
+        let flows = DeviceFlows::default();
+        let flow_id = flows.start("unknown").unwrap_or_default();
+        assert_eq!(flows.poll(&flow_id), Some(DeviceFlowStatus::Pending));
+        assert_eq!(flows.poll(&flow_id), Some(DeviceFlowStatus::Pending));
+
+        flows.set(
+            &flow_id,
+            DeviceFlowStatus::Complete {
+                login: "ledoyen".to_string(),
+            },
+        );
+        assert_eq!(
+            flows.poll(&flow_id),
+            Some(DeviceFlowStatus::Complete {
+                login: "ledoyen".to_string()
+            })
+        );
+        assert_eq!(flows.poll(&flow_id), None);
+        assert_eq!(flows.poll("unknown"), None);
+    }
+}
@@ -0,0 +1,52 @@
+use axum::response::sse::Event;
+use axum::response::{IntoResponse, Response, Sse};
+use futures::{stream, Stream, StreamExt};
+use std::convert::Infallible;
+
+/// Builds an event carrying a chunk of the assistant answer, in the `chat.completion.chunk` format expected by Copilot.
+pub fn text(content: &str) -> Event {
+    Event::default().data(
+        serde_json::json!({
+            "object": "chat.completion.chunk",
+            "choices": [{
+                "index": 0,
+                "delta": { "role": "assistant", "content": content },
+            }],
+        })
+        .to_string(),
+    )
+}
+
+/// Builds an event reporting an error to the Copilot client, which displays its message in place of the answer.
+pub fn error(code: &str, message: &str) -> Event {
+    Event::default().event("copilot_errors").data(
+        serde_json::json!([{
+            "type": "agent",
+            "code": code,
+            "message": message,
+            "identifier": code,
+        }])
+        .to_string(),
+    )
+}
+
+/// Builds the event closing the stream.
+pub fn done() -> Event {
+    Event::default().data("[DONE]")
+}
+
+/// Streams a single Markdown message back to the Copilot client.
+#[must_use]
+pub fn message_response(markdown: &str) -> Response {
+    let events = vec![text(markdown), done()];
+    Sse::new(stream::iter(events.into_iter().map(Ok::<_, Infallible>))).into_response()
+}
+
+/// Streams events back to the Copilot client as they are produced, closing the stream once they are all sent.
+pub fn stream_response<S>(events: S) -> Response
+where
+    S: Stream<Item = Event> + Send + 'static,
+{
+    let events = events.chain(stream::once(async { done() }));
+    Sse::new(events.map(Ok::<_, Infallible>)).into_response()
+}
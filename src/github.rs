@@ -0,0 +1,121 @@
+use anyhow::Context;
+use axum::http::header::{ACCEPT, USER_AGENT};
+use axum::http::StatusCode;
+
+/// Minimal client for the GitHub REST API.
+#[derive(Clone, Debug)]
+pub struct GithubClient {
+    http: reqwest::Client,
+    api_url: String,
+}
+
+#[derive(serde::Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct GithubUser {
+    pub id: u64,
+    pub login: String,
+}
+
+impl GithubClient {
+    pub fn new<T: Into<String>>(api_url: T) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            api_url: api_url.into(),
+        }
+    }
+
+    /// Resolves the user owning the given token.
+    pub async fn user(&self, token: &str) -> anyhow::Result<GithubUser> {
+        let user = self
+            .http
+            .get(format!("{}/user", self.api_url))
+            .bearer_auth(token)
+            .header(USER_AGENT, "toddler-copilot-extension")
+            .header(ACCEPT, "application/vnd.github+json")
+            .send()
+            .await?
+            .error_for_status()
+            .context("[github] Unable to fetch authenticated user")?
+            .json()
+            .await?;
+        Ok(user)
+    }
+
+    /// Revokes the grant of the application for the owner of the given token,
+    /// deleting every token issued to this user.
+    ///
+    /// A grant already revoked is not considered as an error.
+    pub async fn revoke_grant(
+        &self,
+        client_id: &str,
+        client_secret: &str,
+        access_token: &str,
+    ) -> anyhow::Result<()> {
+        let response = self
+            .http
+            .delete(format!("{}/applications/{client_id}/grant", self.api_url))
+            .basic_auth(client_id, Some(client_secret))
+            .header(USER_AGENT, "toddler-copilot-extension")
+            .header(ACCEPT, "application/vnd.github+json")
+            .json(&serde_json::json!({ "access_token": access_token }))
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(());
+        }
+        response
+            .error_for_status()
+            .context("[github] Unable to revoke application grant")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::github::GithubClient;
+    use axum::extract::Path;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::delete;
+    use axum::{Json, Router};
+
+    async fn mock_grant_api(
+        Path(client_id): Path<String>,
+        headers: HeaderMap,
+        Json(body): Json<serde_json::Value>,
+    ) -> StatusCode {
+        let authorized = client_id == "client-id"
+            && headers
+                .get("authorization")
+                .is_some_and(|value| value == "Basic Y2xpZW50LWlkOmNsaWVudC1zZWNyZXQ=");
+        match body["access_token"].as_str() {
+            _ if !authorized => StatusCode::UNAUTHORIZED,
+            Some("ghu_valid") => StatusCode::NO_CONTENT,
+            Some("ghu_revoked") => StatusCode::NOT_FOUND,
+            _ => StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+
+    #[tokio::test]
+    async fn revoke_grant_against_mock_api() -> anyhow::Result<()> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let api_url = format!("http://{}", listener.local_addr()?);
+        let router = Router::new().route("/applications/:client_id/grant", delete(mock_grant_api));
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let client = GithubClient::new(api_url);
+        client
+            .revoke_grant("client-id", "client-secret", "ghu_valid")
+            .await?;
+        client
+            .revoke_grant("client-id", "client-secret", "ghu_revoked")
+            .await?;
+        assert!(client
+            .revoke_grant("client-id", "client-secret", "ghu_invalid")
+            .await
+            .is_err());
+        assert!(client
+            .revoke_grant("client-id", "wrong-secret", "ghu_valid")
+            .await
+            .is_err());
+        Ok(())
+    }
+}
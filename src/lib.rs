@@ -1,7 +1,30 @@
 pub mod agent;
+pub mod app;
+pub mod capture;
 pub mod config;
+pub mod config_validation;
+pub mod conversation;
+pub mod cookie_keys;
 pub mod copilot_public_keys;
+pub mod device_flow;
+pub mod events;
+pub mod github;
+pub mod llm;
+pub mod logout;
 pub mod messages;
+pub mod metrics;
 pub mod oauth;
+pub mod prompt;
+pub mod prompt_templates;
+pub mod redaction;
+pub mod replay;
+pub mod request_tracing;
+pub mod schema_drift;
+pub mod slash_commands;
 pub mod state;
+pub mod thread_store;
+pub mod toddler_agent;
+pub mod token_store;
 pub mod tracing;
+pub mod webhook_events;
+pub mod webhooks;
@@ -0,0 +1,311 @@
+use anyhow::Context;
+use axum::http::header::{AUTHORIZATION, USER_AGENT};
+use futures::{stream, Stream, StreamExt};
+use tracing::field::Empty;
+use tracing::{debug, info_span, Instrument, Span};
+
+/// Client of the Copilot API, answering chat completions on behalf of the user who sent the request.
+#[derive(Clone, Debug)]
+pub struct LlmClient {
+    http: reqwest::Client,
+    api_url: String,
+}
+
+#[derive(serde::Serialize, Clone, Debug, Eq, PartialEq)]
+pub struct LlmMessage {
+    pub role: String,
+    pub content: String,
+}
+
+#[derive(serde::Deserialize, Debug, Default)]
+struct CompletionChunk {
+    #[serde(default)]
+    choices: Vec<CompletionChoice>,
+}
+
+#[derive(serde::Deserialize, Debug, Default)]
+struct CompletionChoice {
+    #[serde(default)]
+    delta: CompletionDelta,
+}
+
+#[derive(serde::Deserialize, Debug, Default)]
+struct CompletionDelta {
+    #[serde(default)]
+    content: Option<String>,
+}
+
+impl LlmClient {
+    #[must_use]
+    pub fn new(api_url: impl Into<String>) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            api_url: api_url.into(),
+        }
+    }
+
+    /// Starts a streamed chat completion, authenticated with the token Copilot sent along the request.
+    ///
+    /// The returned stream yields the chunks of the answer as they arrive,
+    /// within an `llm_completion` span lasting until the answer is entirely streamed.
+    pub async fn stream_completion(
+        &self,
+        token: &str,
+        messages: &[LlmMessage],
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<String>> + Send + 'static> {
+        let span = info_span!(
+            "llm_completion",
+            messages = messages.len(),
+            status = Empty,
+            chunks = Empty,
+            outcome = Empty
+        );
+        let response = self
+            .http
+            .post(format!("{}/chat/completions", self.api_url))
+            .header(AUTHORIZATION, format!("Bearer {token}"))
+            .header(USER_AGENT, "toddler-copilot-extension")
+            .json(&serde_json::json!({ "messages": messages, "stream": true }))
+            .send()
+            .instrument(span.clone())
+            .await
+            .inspect_err(|_| {
+                span.record("outcome", "unreachable");
+            })
+            .context("[llm] Unable to reach the Copilot API")?;
+        span.record("status", response.status().as_u16());
+        let response = response
+            .error_for_status()
+            .inspect_err(|_| {
+                span.record("outcome", "refused");
+            })
+            .context("[llm] Completion refused by the Copilot API")?;
+
+        let mut parser = DeltaParser::default();
+        let deltas = response
+            .bytes_stream()
+            .map(move |chunk| match chunk {
+                Ok(chunk) => parser.push(&chunk).into_iter().map(Ok).collect(),
+                Err(err) => vec![Err(
+                    anyhow::Error::new(err).context("[llm] Completion stream interrupted")
+                )],
+            })
+            .flat_map(stream::iter);
+        Ok(in_span(deltas, span))
+    }
+}
+
+/// Polls the deltas within the span, recording how many were streamed and whether the stream was interrupted
+/// once it ends.
+fn in_span<S>(deltas: S, span: Span) -> impl Stream<Item = anyhow::Result<String>> + Send + 'static
+where
+    S: Stream<Item = anyhow::Result<String>> + Send + 'static,
+{
+    let state = (deltas.boxed(), span, 0_usize, false);
+    stream::unfold(
+        state,
+        |(mut deltas, span, chunks, interrupted)| async move {
+            let Some(delta) = deltas.next().instrument(span.clone()).await else {
+                span.record("chunks", chunks);
+                span.record(
+                    "outcome",
+                    if interrupted {
+                        "interrupted"
+                    } else {
+                        "completed"
+                    },
+                );
+                return None;
+            };
+            let (chunks, interrupted) = match &delta {
+                Ok(_) => (chunks + 1, interrupted),
+                Err(_) => (chunks, true),
+            };
+            Some((delta, (deltas, span, chunks, interrupted)))
+        },
+    )
+}
+
+/// Extracts the content deltas from the server-sent events of a completion stream, whatever the way it is chunked.
+#[derive(Default)]
+pub(crate) struct DeltaParser {
+    buffer: Vec<u8>,
+    done: bool,
+}
+
+impl DeltaParser {
+    pub(crate) fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(chunk);
+        let mut deltas = vec![];
+        while let Some(end) = self.buffer.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let Some(data) = line.trim_end().strip_prefix("data:") else {
+                continue;
+            };
+            let data = data.trim_start();
+            if self.done {
+                continue;
+            }
+            if data == "[DONE]" {
+                self.done = true;
+                continue;
+            }
+            match serde_json::from_str::<CompletionChunk>(data) {
+                Ok(chunk) => deltas.extend(
+                    chunk
+                        .choices
+                        .into_iter()
+                        .filter_map(|choice| choice.delta.content)
+                        .filter(|content| !content.is_empty()),
+                ),
+                Err(err) => debug!(error = ?err, "[llm] Ignoring unparseable completion chunk"),
+            }
+        }
+        deltas
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::llm::{DeltaParser, LlmClient, LlmMessage};
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::post;
+    use axum::Router;
+    use futures::StreamExt;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Id, Record};
+    use tracing::Subscriber;
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::Layer;
+
+    const UPSTREAM_EVENTS: &str = "data: {\"choices\":[{\"index\":0,\"delta\":{\"role\":\"assistant\",\"content\":\"\"}}]}\n\n\
+        data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hello\"}}]}\n\n\
+        data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\" wörld\"}}]}\n\n\
+        data: [DONE]\n\n";
+
+    /// Outcomes recorded on spans, and their closing.
+    #[derive(Clone, Default)]
+    struct SpanEvents(Arc<Mutex<Vec<String>>>);
+
+    impl SpanEvents {
+        fn take(&self) -> Vec<String> {
+            std::mem::take(
+                &mut *self
+                    .0
+                    .lock()
+                    .unwrap_or_else(std::sync::PoisonError::into_inner),
+            )
+        }
+    }
+
+    impl<S: Subscriber> Layer<S> for SpanEvents {
+        fn on_record(&self, _: &Id, values: &Record<'_>, _: Context<'_, S>) {
+            struct Outcome(Option<String>);
+            impl Visit for Outcome {
+                fn record_str(&mut self, field: &Field, value: &str) {
+                    if field.name() == "outcome" {
+                        self.0 = Some(value.to_string());
+                    }
+                }
+                fn record_debug(&mut self, _: &Field, _: &dyn std::fmt::Debug) {}
+            }
+            let mut outcome = Outcome(None);
+            values.record(&mut outcome);
+            if let Some(outcome) = outcome.0 {
+                self.0
+                    .lock()
+                    .unwrap_or_else(std::sync::PoisonError::into_inner)
+                    .push(outcome);
+            }
+        }
+
+        fn on_close(&self, _: Id, _: Context<'_, S>) {
+            self.0
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner)
+                .push("closed".to_string());
+        }
+    }
+
+    async fn mock_api() -> anyhow::Result<String> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let api_url = format!("http://{}", listener.local_addr()?);
+        let router = Router::new().route(
+            "/chat/completions",
+            post(|headers: HeaderMap| async move {
+                if headers
+                    .get("authorization")
+                    .is_some_and(|value| value == "Bearer ghu_valid")
+                {
+                    (StatusCode::OK, UPSTREAM_EVENTS)
+                } else {
+                    (StatusCode::UNAUTHORIZED, "")
+                }
+            }),
+        );
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        Ok(api_url)
+    }
+
+    fn messages() -> [LlmMessage; 1] {
+        [LlmMessage {
+            role: "user".to_string(),
+            content: "Say hello".to_string(),
+        }]
+    }
+
+    #[test]
+    fn deltas_are_extracted_whatever_the_chunking() {
+        for chunk_size in [1, 7, UPSTREAM_EVENTS.len()] {
+            let mut parser = DeltaParser::default();
+            let deltas: Vec<String> = UPSTREAM_EVENTS
+                .as_bytes()
+                .chunks(chunk_size)
+                .flat_map(|chunk| parser.push(chunk))
+                .collect();
+            assert_eq!(deltas, vec!["Hello", " wörld"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn stream_completion_against_mock_api() -> anyhow::Result<()> {
+        let client = LlmClient::new(mock_api().await?);
+        let messages = messages();
+        let deltas = client.stream_completion("ghu_valid", &messages).await?;
+        let deltas: Vec<String> = deltas
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<anyhow::Result<_>>()?;
+        assert_eq!(deltas.concat(), "Hello wörld");
+
+        assert!(client
+            .stream_completion("ghu_invalid", &messages)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn completion_span_lasts_until_the_answer_is_streamed() -> anyhow::Result<()> {
+        let client = LlmClient::new(mock_api().await?);
+        let events = SpanEvents::default();
+        let _subscriber =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(events.clone()));
+
+        let deltas = client.stream_completion("ghu_valid", &messages()).await?;
+        assert_eq!(events.take(), Vec::<String>::new());
+        let deltas: Vec<_> = deltas.collect().await;
+        assert_eq!(deltas.len(), 2);
+        assert_eq!(events.take(), vec!["completed", "closed"]);
+
+        assert!(client
+            .stream_completion("ghu_invalid", &messages())
+            .await
+            .is_err());
+        assert_eq!(events.take(), vec!["refused", "closed"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,78 @@
+use crate::oauth::{GH_RETURN_TO_COOKIE, GH_SESSION_COOKIE, GH_STATE_COOKIE};
+use crate::state::AppState;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum_extra::extract::cookie::Cookie;
+use axum_extra::extract::PrivateCookieJar;
+use headers::authorization::Bearer;
+use headers::{Authorization, HeaderMapExt};
+use tracing::{error, info, warn};
+
+/// Unlinks the account of the caller, identified either by a GitHub token sent as bearer or by the session cookie.
+pub async fn logout(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    jar: PrivateCookieJar,
+) -> (StatusCode, PrivateCookieJar) {
+    let user_id = match headers.typed_get::<Authorization<Bearer>>() {
+        Some(bearer) => match state.github_client.user(bearer.token()).await {
+            Ok(user) => Some(user.id),
+            Err(err) => {
+                warn!(error = ?err, "[http] logout: Unable to identify the bearer");
+                None
+            }
+        },
+        None => state
+            .cookie_keys
+            .get(&jar, &headers, GH_SESSION_COOKIE)
+            .and_then(|cookie| cookie.value().parse().ok()),
+    };
+
+    let jar = jar
+        .remove(Cookie::build(GH_SESSION_COOKIE).path("/"))
+        .remove(Cookie::build(GH_STATE_COOKIE).path("/"))
+        .remove(Cookie::build(GH_RETURN_TO_COOKIE).path("/"));
+
+    let Some(user_id) = user_id else {
+        return (StatusCode::UNAUTHORIZED, jar);
+    };
+
+    match revoke_user_tokens(&state, user_id, "logout endpoint").await {
+        Ok(_) => (StatusCode::NO_CONTENT, jar),
+        Err(err) => {
+            error!(error = ?err, "[http] logout: Unable to revoke grant");
+            (StatusCode::BAD_GATEWAY, jar)
+        }
+    }
+}
+
+/// Deletes the stored tokens of the given user and revokes the grant on GitHub.
+///
+/// Returns `false` when no token was stored for this user.
+/// Stored tokens are deleted even if the revocation fails.
+pub async fn revoke_user_tokens(
+    state: &AppState,
+    user_id: u64,
+    reason: &str,
+) -> anyhow::Result<bool> {
+    let Some(token) = state.token_store.remove(user_id) else {
+        return Ok(false);
+    };
+    let revocation = state
+        .github_client
+        .revoke_grant(
+            &state.config.github_app_client_id,
+            &state.config.github_app_client_secret,
+            &token.access_token,
+        )
+        .await;
+    info!(
+        target: "audit",
+        user_id,
+        login = token.login,
+        reason,
+        revoked_on_github = revocation.is_ok(),
+        "Token revoked"
+    );
+    revocation.map(|()| true)
+}
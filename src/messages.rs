@@ -1,14 +1,179 @@
+use crate::thread_store::ThreadEntry;
+use anyhow::anyhow;
+use sha2::{Digest, Sha256};
+use std::fmt::{Display, Formatter};
+
 #[derive(serde::Deserialize, serde::Serialize, Eq, PartialEq, Debug)]
 pub struct ChatRequest {
     pub copilot_thread_id: String,
     pub messages: Vec<ChatMessage>,
+    /// Name of the Copilot extension the request is addressed to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent: Option<String>,
+}
+
+/// How references that do not match their expected shape are handled when parsing a request.
+#[derive(serde::Deserialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ParseMode {
+    /// Such references are kept as [`CopilotReference::Unknown`], with a diagnostic
+    #[default]
+    Lenient,
+    /// Such references, and the ones of unknown types, fail the parsing
+    Strict,
+}
+
+/// Problem found while parsing a reference, with the path of the offending field from the root of the request.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ParseDiagnostic {
+    pub path: String,
+    pub message: String,
+}
+
+impl Display for ParseDiagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
 }
 
 impl ChatRequest {
+    /// Parses a request leniently, see [`ParseMode::Lenient`].
     pub fn parse(content: &str) -> anyhow::Result<ChatRequest> {
+        Self::parse_with_mode(content, ParseMode::Lenient)
+    }
+
+    pub fn parse_with_mode(content: &str, mode: ParseMode) -> anyhow::Result<ChatRequest> {
         let result = &mut serde_json::Deserializer::from_str(content);
-        let result: Result<ChatRequest, _> = serde_path_to_error::deserialize(result);
-        Ok(result?)
+        let request: ChatRequest = serde_path_to_error::deserialize(result)?;
+        if mode == ParseMode::Strict {
+            let diagnostics = request.diagnostics();
+            if !diagnostics.is_empty() {
+                return Err(anyhow!(diagnostics
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join("; ")));
+            }
+        }
+        Ok(request)
+    }
+
+    /// Problems found in the references that could not be parsed, or whose type is unknown.
+    #[must_use]
+    pub fn diagnostics(&self) -> Vec<ParseDiagnostic> {
+        self.messages
+            .iter()
+            .enumerate()
+            .flat_map(|(message_index, message)| {
+                message.copilot_references.iter().enumerate().filter_map(
+                    move |(reference_index, reference)| match reference {
+                        CopilotReference::Unknown(unknown) => Some(ParseDiagnostic {
+                            path: format!(
+                                "messages[{message_index}].copilot_references[{reference_index}]{}",
+                                unknown
+                                    .error
+                                    .as_ref()
+                                    .filter(|error| !error.path.is_empty())
+                                    .map_or_else(String::new, |error| format!(".{}", error.path))
+                            ),
+                            message: unknown.error.as_ref().map_or_else(
+                                || {
+                                    format!(
+                                        "unknown reference type {}",
+                                        unknown.reference_type.as_deref().unwrap_or_default()
+                                    )
+                                },
+                                |error| error.message.clone(),
+                            ),
+                        }),
+                        _ => None,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    /// Key of the conversation, the `copilot_thread_id` when sent, otherwise derived from the request and its caller.
+    ///
+    /// See [`ThreadKey::Derived`] for the guarantees of derived keys.
+    #[must_use]
+    pub fn thread_key(&self, user_id: u64, integration_id: Option<&str>) -> ThreadKey {
+        if !self.copilot_thread_id.is_empty() {
+            return ThreadKey::Copilot(self.copilot_thread_id.clone());
+        }
+        let first_user_message = self
+            .messages
+            .iter()
+            .find(|message| {
+                message.role == Role::User
+                    && message.name.is_none()
+                    && !message.content.trim().is_empty()
+            })
+            .map_or("", |message| message.content.trim());
+        let mut hasher = Sha256::new();
+        for field in [
+            THREAD_KEY_DOMAIN.as_bytes(),
+            &user_id.to_be_bytes(),
+            integration_id.unwrap_or_default().as_bytes(),
+            first_user_message.as_bytes(),
+        ] {
+            // fields are prefixed by their length, so that no two different sets of fields are hashed the same
+            hasher.update((field.len() as u64).to_be_bytes());
+            hasher.update(field);
+        }
+        ThreadKey::Derived(format!(
+            "{DERIVED_THREAD_KEY_PREFIX}{}",
+            hex::encode(hasher.finalize())
+        ))
+    }
+
+    /// URL of the page the user is chatting from, only sent by github.com.
+    #[must_use]
+    pub fn current_url(&self) -> Option<&str> {
+        self.messages
+            .iter()
+            .flat_map(|message| &message.copilot_references)
+            .find_map(|reference| match reference {
+                CopilotReference::GithubCurrentUrl(reference) => Some(reference.data.url.as_str()),
+                _ => None,
+            })
+    }
+}
+
+/// Separates the hashes of thread keys from any other use of the same fields.
+const THREAD_KEY_DOMAIN: &str = "toddler-copilot-extension/thread-key/v1";
+const DERIVED_THREAD_KEY_PREFIX: &str = "derived:";
+
+/// Identity of a conversation, keying what is kept across its requests.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub enum ThreadKey {
+    /// `copilot_thread_id` sent by the client
+    Copilot(String),
+    /// Key derived when the client sends an empty `copilot_thread_id`, as the IDEs of `JetBrains` do,
+    /// from the caller, the integration id and the first user message, which are resent with every request of a conversation.
+    ///
+    /// * Requests of the same conversation always get the same key.
+    /// * Conversations of different callers, from different integrations, or starting with different messages
+    ///   get different keys, short of a SHA-256 collision.
+    /// * Conversations of the same caller in the same integration starting with the same message share their key,
+    ///   as nothing else tells them apart.
+    /// * Derived keys are prefixed with `derived:`, so they only match a `copilot_thread_id` of the same form,
+    ///   which Copilot does not send as it uses UUIDs.
+    Derived(String),
+}
+
+impl ThreadKey {
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Copilot(key) | Self::Derived(key) => key,
+        }
+    }
+}
+
+impl Display for ThreadKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
     }
 }
 
@@ -16,10 +181,16 @@ impl ChatRequest {
 pub struct ChatMessage {
     pub role: Role,
     pub content: String,
+    /// Set on pseudo messages added by Copilot, such as `_session`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
     pub copilot_references: Vec<CopilotReference>,
+    /// Answer recorded by the extension for this assistant message, see [`crate::thread_store::ThreadStore::merge`]
+    #[serde(skip)]
+    pub recorded: Option<ThreadEntry>,
 }
 
-#[derive(serde::Deserialize, serde::Serialize, Eq, PartialEq, Debug)]
+#[derive(serde::Deserialize, serde::Serialize, Clone, Eq, PartialEq, Debug)]
 #[serde(rename_all = "snake_case")]
 pub enum Role {
     User,
@@ -30,50 +201,102 @@ pub enum Role {
 
 #[derive(serde::Serialize, Eq, PartialEq, Debug)]
 #[serde(tag = "type")]
+#[allow(clippy::large_enum_variant)]
 pub enum CopilotReference {
     #[serde(rename = "github.repository")]
     GithubRepository(CopilotReferenceData<GithubRepository>),
     #[serde(rename = "client.file")]
     ClientFile(CopilotReferenceData<ClientFile>),
+    #[serde(rename = "client.selection")]
+    ClientSelection(CopilotReferenceData<ClientSelection>),
+    #[serde(rename = "github.current-url")]
+    GithubCurrentUrl(CopilotReferenceData<GithubCurrentUrl>),
+    /// Reference of an unknown type, or not matching the shape expected for its type
     #[serde(untagged)]
-    Unknown(String),
+    Unknown(UnknownReference),
+}
+
+/// Reference kept as sent, serialized back as is.
+#[derive(Eq, PartialEq, Debug)]
+pub struct UnknownReference {
+    /// Value of the `type` field, when it is a string
+    pub reference_type: Option<String>,
+    pub value: serde_json::Value,
+    /// Why the reference did not match the shape expected for its type, `None` when the type is unknown
+    pub error: Option<ReferenceError>,
+}
+
+/// Error raised while parsing a reference, the path being relative to the reference.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct ReferenceError {
+    pub path: String,
+    pub message: String,
+}
+
+impl serde::Serialize for UnknownReference {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.value.serialize(serializer)
+    }
 }
 
 impl<'de> serde::Deserialize<'de> for CopilotReference {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-        where
-            D: serde::Deserializer<'de>,
+    where
+        D: serde::Deserializer<'de>,
     {
         let value: serde_json::Value = serde::Deserialize::deserialize(deserializer)?;
-        let _type = value.get("type").unwrap();
-        match _type.as_str().unwrap() {
-            "github.repository" => {
-                return if let Ok(reference) = serde_json::from_value::<CopilotReferenceData<GithubRepository>>(value.clone()) {
-                    Ok(CopilotReference::GithubRepository(reference))
-                } else {
-                    Ok(CopilotReference::Unknown(value.to_string()))
-                }
-            }
-            "client.file" => {
-                return if let Ok(reference) = serde_json::from_value::<CopilotReferenceData<ClientFile>>(value.clone()) {
-                    Ok(CopilotReference::ClientFile(reference))
-                } else {
-                    Ok(CopilotReference::Unknown(value.to_string()))
-                }
+        let reference_type = value.get("type").and_then(|t| t.as_str()).map(String::from);
+        let result = match reference_type.as_deref() {
+            Some("github.repository") => parse_reference(&value).map(Self::GithubRepository),
+            Some("client.file") => parse_reference(&value).map(Self::ClientFile),
+            Some("client.selection") => parse_reference(&value).map(Self::ClientSelection),
+            Some("github.current-url") => parse_reference(&value).map(Self::GithubCurrentUrl),
+            Some(_) => {
+                return Ok(Self::Unknown(UnknownReference {
+                    reference_type,
+                    value,
+                    error: None,
+                }))
             }
-            _ => Ok(CopilotReference::Unknown(value.to_string())),
-        }
+            None => Err(ReferenceError {
+                path: "type".to_string(),
+                message: "missing or not a string".to_string(),
+            }),
+        };
+        Ok(result.unwrap_or_else(|error| {
+            Self::Unknown(UnknownReference {
+                reference_type,
+                value,
+                error: Some(error),
+            })
+        }))
     }
 }
 
+fn parse_reference<T: serde::de::DeserializeOwned>(
+    value: &serde_json::Value,
+) -> Result<CopilotReferenceData<T>, ReferenceError> {
+    serde_path_to_error::deserialize(value).map_err(|err| {
+        let path = err.path().to_string();
+        ReferenceError {
+            // the root of the reference is displayed as '.'
+            path: if path == "." { String::new() } else { path },
+            message: err.into_inner().to_string(),
+        }
+    })
+}
+
 #[derive(serde::Deserialize, serde::Serialize, Eq, PartialEq, Debug, Default)]
 pub struct CopilotReferenceData<T> {
     //#[serde(rename = "type")]
     //_type: String,
-    data: T,
-    id: String,
-    is_implicit: bool,
-    metadata: CopilotReferenceMetadata,
+    pub data: T,
+    pub id: String,
+    pub is_implicit: bool,
+    pub metadata: CopilotReferenceMetadata,
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Eq, PartialEq, Debug, Default)]
@@ -104,7 +327,22 @@ pub struct GithubRepository {
     pub ref_info: GithubRefInfo,
     pub visibility: String,
     #[serde(default)]
-    pub languages: Option<String>,
+    pub languages: Option<Vec<GithubLanguage>>,
+}
+
+impl GithubRepository {
+    /// Name of the branch or tag, `None` when not sent, as from the IDEs.
+    #[must_use]
+    pub fn git_ref(&self) -> Option<&str> {
+        Some(self.ref_info.name.as_str()).filter(|name| !name.is_empty())
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Eq, PartialEq, Debug)]
+pub struct GithubLanguage {
+    pub name: String,
+    /// Share of the code, in percent
+    pub percent: serde_json::Number,
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Eq, PartialEq, Debug, Default)]
@@ -120,10 +358,36 @@ pub struct ClientFile {
     pub language: String,
 }
 
+/// Code selected in the editor, its `id` being the name of the file.
+#[derive(serde::Deserialize, serde::Serialize, Eq, PartialEq, Debug, Default)]
+pub struct ClientSelection {
+    pub content: String,
+    pub start: SelectionPosition,
+    pub end: SelectionPosition,
+}
+
+/// Position in a file, lines being counted from 1 by Copilot.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub struct SelectionPosition {
+    pub line: u32,
+    pub col: u32,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Eq, PartialEq, Debug, Default)]
+pub struct GithubCurrentUrl {
+    pub url: String,
+}
+
+#[cfg(test)]
+mod proptests;
+
 #[cfg(test)]
 mod tests {
+    use crate::messages::{
+        ChatMessage, ChatRequest, ClientFile, CopilotReference, CopilotReferenceData,
+        GithubRepository, ParseMode, Role, ThreadKey,
+    };
     use std::fs;
-    use crate::messages::{ChatMessage, ChatRequest, ClientFile, CopilotReference, CopilotReferenceData, GithubRepository, Role};
 
     #[test]
     fn parse_chat_request_from_vs_code() -> anyhow::Result<()> {
@@ -133,25 +397,26 @@ mod tests {
             req,
             ChatRequest {
                 copilot_thread_id: "ca746c32-a78c-4d06-92b1-af5c31dadfde".to_string(),
-                messages: vec![
-                    ChatMessage {
-                        role: Role::User,
-                        content: "help with this file".to_string(),
-                        copilot_references: vec![
-                            CopilotReference::GithubRepository(CopilotReferenceData {
-                                data: GithubRepository {
-                                    _type: "repository".to_string(),
-                                    id: 675110970,
-                                    name: "korekto-frontend".to_string(),
-                                    owner_login: "korekto".to_string(),
-                                    ..GithubRepository::default()
-                                },
-                                id: "korekto/korekto-frontend".to_string(),
-                                ..CopilotReferenceData::default()
-                            })
-                        ],
-                    }
-                ],
+                messages: vec![ChatMessage {
+                    role: Role::User,
+                    content: "help with this file".to_string(),
+                    name: None,
+                    copilot_references: vec![CopilotReference::GithubRepository(
+                        CopilotReferenceData {
+                            data: GithubRepository {
+                                _type: "repository".to_string(),
+                                id: 675110970,
+                                name: "korekto-frontend".to_string(),
+                                owner_login: "korekto".to_string(),
+                                ..GithubRepository::default()
+                            },
+                            id: "korekto/korekto-frontend".to_string(),
+                            ..CopilotReferenceData::default()
+                        }
+                    )],
+                    recorded: None,
+                }],
+                agent: Some("mais-arreeeeeeeeteuuuu".to_string()),
             }
         );
         Ok(())
@@ -169,6 +434,7 @@ mod tests {
                     ChatMessage {
                         role: Role::User,
                         content: "coucou".to_string(),
+                        name: None,
                         copilot_references: vec![
                             CopilotReference::GithubRepository(CopilotReferenceData {
                                 data: GithubRepository {
@@ -190,10 +456,248 @@ mod tests {
                                 ..CopilotReferenceData::default()
                             }),
                         ],
+                        recorded: None,
                     }
                 ],
+                agent: Some("mais-arreeeeeeeeteuuuu".to_string()),
             }
         );
         Ok(())
     }
+
+    fn request_with_reference(reference: &serde_json::Value) -> String {
+        serde_json::json!({
+            "copilot_thread_id": "",
+            "messages": [{ "role": "user", "content": "hello", "copilot_references": [reference] }],
+        })
+        .to_string()
+    }
+
+    fn repository_reference() -> serde_json::Value {
+        serde_json::json!({
+            "type": "github.repository",
+            "data": {
+                "type": "repository", "id": 898581080, "name": "toddler-copilot-extension",
+                "ownerLogin": "ledoyen", "ownerType": "User", "readmePath": "README.md", "description": "",
+                "commitOID": "5a7d814", "ref": "refs/heads/main", "refInfo": { "name": "main", "type": "branch" },
+                "visibility": "public", "languages": [{ "name": "Rust", "percent": 94.5 }],
+            },
+            "id": "ledoyen/toddler-copilot-extension",
+            "is_implicit": false,
+            "metadata": { "display_name": "", "display_icon": "", "display_url": "" },
+        })
+    }
+
+    #[test]
+    fn repository_languages_are_parsed() -> anyhow::Result<()> {
+        let payload = fs::read_to_string("samples/chat_request_from_web_with_repository.json")?;
+        let req = ChatRequest::parse_with_mode(&payload, ParseMode::Strict)?;
+        let CopilotReference::GithubRepository(repository) = &req.messages[0].copilot_references[0]
+        else {
+            panic!(
+                "not a repository: {:?}",
+                req.messages[0].copilot_references[0]
+            );
+        };
+        let languages = repository.data.languages.as_deref().unwrap_or_default();
+        assert_eq!(
+            languages
+                .iter()
+                .map(|l| l.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["Rust", "Just"]
+        );
+        assert_eq!(languages[0].percent.as_f64(), Some(94.5));
+        Ok(())
+    }
+
+    #[test]
+    fn samples_of_known_references_parse_strictly() -> anyhow::Result<()> {
+        for sample in [
+            "samples/chat_request_from_ij.json",
+            "samples/chat_request_from_vs_code.json",
+            "samples/chat_request_from_vs_code_with_selection.json",
+            "samples/chat_request_from_vs_code_withcurrent_editor_and_file_context.json",
+            "samples/chat_request_from_web.json",
+        ] {
+            let payload = fs::read_to_string(sample)?;
+            ChatRequest::parse_with_mode(&payload, ParseMode::Strict)
+                .map_err(|err| err.context(sample))?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_reference_is_kept_with_its_path() -> anyhow::Result<()> {
+        let mut reference = repository_reference();
+        reference["data"]["id"] = "898581080".into();
+        let payload = request_with_reference(&reference);
+
+        let req = ChatRequest::parse(&payload)?;
+        let CopilotReference::Unknown(unknown) = &req.messages[0].copilot_references[0] else {
+            panic!("parsed anyway");
+        };
+        assert_eq!(unknown.reference_type.as_deref(), Some("github.repository"));
+        assert_eq!(unknown.value, reference);
+        let diagnostics = req.diagnostics();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(
+            diagnostics[0].path,
+            "messages[0].copilot_references[0].data.id"
+        );
+        assert!(diagnostics[0].message.starts_with("invalid type: string"));
+
+        let err = ChatRequest::parse_with_mode(&payload, ParseMode::Strict).err();
+        assert_eq!(
+            err.map(|err| err.to_string()),
+            Some(diagnostics[0].to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn references_without_type_do_not_panic() -> anyhow::Result<()> {
+        for reference in [
+            serde_json::json!({ "id": "orphan" }),
+            serde_json::json!({ "type": 42 }),
+            serde_json::json!("not even an object"),
+            serde_json::json!({ "type": "github.repository" }),
+        ] {
+            let req = ChatRequest::parse(&request_with_reference(&reference))?;
+            assert!(matches!(
+                &req.messages[0].copilot_references[0],
+                CopilotReference::Unknown(unknown) if unknown.error.is_some() && unknown.value == reference
+            ));
+        }
+        let req = ChatRequest::parse(&request_with_reference(
+            &serde_json::json!({ "id": "orphan" }),
+        ))?;
+        assert_eq!(
+            req.diagnostics()[0].to_string(),
+            "messages[0].copilot_references[0].type: missing or not a string"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_reference_types_are_kept_as_sent() -> anyhow::Result<()> {
+        let reference =
+            serde_json::json!({ "type": "github.discussion", "data": { "number": 12 } });
+        let payload = request_with_reference(&reference);
+
+        let req = ChatRequest::parse(&payload)?;
+        assert_eq!(
+            serde_json::to_value(&req.messages[0].copilot_references[0])?,
+            reference
+        );
+        assert_eq!(
+            req.diagnostics()[0].message,
+            "unknown reference type github.discussion"
+        );
+        assert!(ChatRequest::parse_with_mode(&payload, ParseMode::Strict).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn strict_mode_reports_every_diagnostic() -> anyhow::Result<()> {
+        let payload = serde_json::json!({
+            "copilot_thread_id": "",
+            "messages": [{
+                "role": "user",
+                "content": "hello",
+                "copilot_references": [
+                    { "type": "github.discussion", "data": { "number": 12 } },
+                    { "id": "orphan" },
+                ],
+            }],
+        })
+        .to_string();
+
+        let err = ChatRequest::parse_with_mode(&payload, ParseMode::Strict).err();
+        assert_eq!(
+            err.map(|err| err.to_string()),
+            Some(
+                "messages[0].copilot_references[0]: unknown reference type github.discussion; \
+                 messages[0].copilot_references[1].type: missing or not a string"
+                    .to_string()
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn copilot_thread_id_is_the_thread_key_when_sent() -> anyhow::Result<()> {
+        let payload = fs::read_to_string("samples/chat_request_from_vs_code.json")?;
+        let request = ChatRequest::parse(&payload)?;
+        assert_eq!(
+            request.thread_key(1, Some("vscode-chat")),
+            ThreadKey::Copilot("ca746c32-a78c-4d06-92b1-af5c31dadfde".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn thread_key_is_derived_when_copilot_thread_id_is_empty() -> anyhow::Result<()> {
+        let payload = fs::read_to_string("samples/chat_request_from_ij.json")?;
+        let first = ChatRequest::parse(&payload)?;
+        let key = first.thread_key(1, Some("jetbrains-chat"));
+        assert!(matches!(&key, ThreadKey::Derived(derived) if derived.starts_with("derived:")));
+
+        // the next request of the conversation resends the history
+        let mut next = ChatRequest::parse(&payload)?;
+        for (role, content) in [(Role::Assistant, ""), (Role::User, "and then?")] {
+            next.messages.push(ChatMessage {
+                role,
+                content: content.to_string(),
+                name: None,
+                copilot_references: vec![],
+                recorded: None,
+            });
+        }
+        assert_eq!(next.thread_key(1, Some("jetbrains-chat")), key);
+
+        assert_ne!(first.thread_key(2, Some("jetbrains-chat")), key);
+        assert_ne!(first.thread_key(1, Some("vscode-chat")), key);
+        assert_ne!(first.thread_key(1, None), key);
+        let mut other = ChatRequest::parse(&payload)?;
+        other.messages[0].content = "hello".to_string();
+        assert_ne!(other.thread_key(1, Some("jetbrains-chat")), key);
+        Ok(())
+    }
+
+    #[test]
+    fn derived_thread_keys_do_not_mix_fields() -> anyhow::Result<()> {
+        let request = |content: &str| ChatRequest {
+            copilot_thread_id: String::new(),
+            messages: vec![ChatMessage {
+                role: Role::User,
+                content: content.to_string(),
+                name: None,
+                copilot_references: vec![],
+                recorded: None,
+            }],
+            agent: None,
+        };
+        assert_ne!(
+            request("c").thread_key(1, Some("ab")),
+            request("bc").thread_key(1, Some("a"))
+        );
+        // pseudo messages, such as the session one carrying the current time, are ignored
+        let mut with_session = request("hello");
+        with_session.messages.insert(
+            0,
+            ChatMessage {
+                role: Role::User,
+                content: "Current Date and Time (UTC): 2024-12-09 18:48:35".to_string(),
+                name: Some("_session".to_string()),
+                copilot_references: vec![],
+                recorded: None,
+            },
+        );
+        assert_eq!(
+            with_session.thread_key(1, None),
+            request("hello").thread_key(1, None)
+        );
+        Ok(())
+    }
 }
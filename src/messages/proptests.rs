@@ -0,0 +1,251 @@
+use crate::messages::{
+    ChatMessage, ChatRequest, ClientFile, ClientSelection, CopilotReference, CopilotReferenceData,
+    CopilotReferenceMetadata, GithubCurrentUrl, GithubLanguage, GithubRefInfo, GithubRepository,
+    Role, SelectionPosition, UnknownReference,
+};
+use proptest::prelude::*;
+use serde_json::{Number, Value};
+
+/// Reference types parsed into a dedicated variant, never generated as unknown ones.
+const KNOWN_REFERENCE_TYPES: [&str; 4] = [
+    "github.repository",
+    "client.file",
+    "client.selection",
+    "github.current-url",
+];
+
+fn text() -> impl Strategy<Value = String> {
+    "\\PC{0,16}"
+}
+
+fn number() -> impl Strategy<Value = Number> {
+    prop_oneof![
+        any::<u64>().prop_map(Number::from),
+        any::<i64>().prop_map(Number::from),
+        (-1e6f64..1e6).prop_filter_map("not finite", Number::from_f64),
+    ]
+}
+
+/// Any JSON value, nested a few levels deep.
+fn json_value() -> impl Strategy<Value = Value> {
+    let leaf = prop_oneof![
+        Just(Value::Null),
+        any::<bool>().prop_map(Value::Bool),
+        number().prop_map(Value::Number),
+        text().prop_map(Value::String),
+    ];
+    leaf.prop_recursive(3, 24, 4, |inner| {
+        prop_oneof![
+            prop::collection::vec(inner.clone(), 0..4).prop_map(Value::Array),
+            prop::collection::btree_map("[a-z_]{1,8}", inner, 0..4)
+                .prop_map(|fields| Value::Object(fields.into_iter().collect())),
+        ]
+    })
+}
+
+fn reference_data<T: std::fmt::Debug>(
+    data: impl Strategy<Value = T>,
+) -> impl Strategy<Value = CopilotReferenceData<T>> {
+    (data, text(), any::<bool>(), (text(), text(), text())).prop_map(
+        |(data, id, is_implicit, (display_name, display_icon, display_url))| CopilotReferenceData {
+            data,
+            id,
+            is_implicit,
+            metadata: CopilotReferenceMetadata {
+                display_name,
+                display_icon,
+                display_url,
+            },
+        },
+    )
+}
+
+fn github_repository() -> impl Strategy<Value = GithubRepository> {
+    let language = (text(), number()).prop_map(|(name, percent)| GithubLanguage { name, percent });
+    (
+        (text(), any::<u64>(), text(), text(), text(), text()),
+        (text(), text(), text(), (text(), text()), text()),
+        prop::option::of(prop::collection::vec(language, 0..3)),
+    )
+        .prop_map(
+            |(
+                (_type, id, name, owner_login, owner_type, readme_path),
+                (description, commit_oid, _ref, (ref_type, ref_name), visibility),
+                languages,
+            )| GithubRepository {
+                _type,
+                id,
+                name,
+                owner_login,
+                owner_type,
+                readme_path,
+                description,
+                commit_oid,
+                _ref,
+                ref_info: GithubRefInfo {
+                    _type: ref_type,
+                    name: ref_name,
+                },
+                visibility,
+                languages,
+            },
+        )
+}
+
+fn client_selection() -> impl Strategy<Value = ClientSelection> {
+    let position =
+        (any::<u32>(), any::<u32>()).prop_map(|(line, col)| SelectionPosition { line, col });
+    (text(), position.clone(), position).prop_map(|(content, start, end)| ClientSelection {
+        content,
+        start,
+        end,
+    })
+}
+
+/// References of types the extension does not know, kept as sent.
+fn unknown_reference() -> impl Strategy<Value = UnknownReference> {
+    (
+        "[a-z]{1,8}\\.[a-z-]{1,10}".prop_filter("known type", |t| {
+            !KNOWN_REFERENCE_TYPES.contains(&t.as_str())
+        }),
+        json_value(),
+    )
+        .prop_map(|(reference_type, data)| UnknownReference {
+            value: serde_json::json!({ "type": reference_type, "data": data }),
+            reference_type: Some(reference_type),
+            error: None,
+        })
+}
+
+fn copilot_reference() -> impl Strategy<Value = CopilotReference> {
+    prop_oneof![
+        reference_data(github_repository()).prop_map(CopilotReference::GithubRepository),
+        reference_data(
+            (text(), text()).prop_map(|(content, language)| ClientFile { content, language })
+        )
+        .prop_map(CopilotReference::ClientFile),
+        reference_data(client_selection()).prop_map(CopilotReference::ClientSelection),
+        reference_data(text().prop_map(|url| GithubCurrentUrl { url }))
+            .prop_map(CopilotReference::GithubCurrentUrl),
+        unknown_reference().prop_map(CopilotReference::Unknown),
+    ]
+}
+
+fn role() -> impl Strategy<Value = Role> {
+    prop_oneof![
+        Just(Role::User),
+        Just(Role::Assistant),
+        "[a-z_]{1,10}"
+            .prop_filter("known role", |role| role != "user" && role != "assistant")
+            .prop_map(Role::Unknown),
+    ]
+}
+
+fn chat_message() -> impl Strategy<Value = ChatMessage> {
+    (
+        role(),
+        text(),
+        prop::option::of(text()),
+        prop::collection::vec(copilot_reference(), 0..4),
+    )
+        .prop_map(|(role, content, name, copilot_references)| ChatMessage {
+            role,
+            content,
+            name,
+            copilot_references,
+            recorded: None,
+        })
+}
+
+fn chat_request() -> impl Strategy<Value = ChatRequest> {
+    (
+        text(),
+        prop::collection::vec(chat_message(), 0..4),
+        prop::option::of(text()),
+    )
+        .prop_map(|(copilot_thread_id, messages, agent)| ChatRequest {
+            copilot_thread_id,
+            messages,
+            agent,
+        })
+}
+
+/// References built from arbitrary values, of known types or not, mostly not matching the expected shapes.
+fn arbitrary_reference() -> impl Strategy<Value = Value> {
+    let reference_type = prop_oneof![
+        prop::sample::select(KNOWN_REFERENCE_TYPES.to_vec()).prop_map(Value::from),
+        json_value(),
+    ];
+    (reference_type, json_value(), json_value(), json_value()).prop_map(
+        |(reference_type, data, id, metadata)| {
+            serde_json::json!({ "type": reference_type, "data": data, "id": id, "is_implicit": false, "metadata": metadata })
+        },
+    )
+}
+
+fn request_with_references(references: Vec<Value>) -> String {
+    serde_json::json!({
+        "copilot_thread_id": "",
+        "messages": [{ "role": "user", "content": "", "copilot_references": references }],
+    })
+    .to_string()
+}
+
+/// Parses leniently, failing the test case rather than panicking on errors.
+fn parse(content: &str) -> Result<ChatRequest, TestCaseError> {
+    ChatRequest::parse(content).map_err(|err| TestCaseError::fail(format!("{err:#}")))
+}
+
+proptest! {
+    #[test]
+    fn generated_requests_round_trip(request in chat_request()) {
+        let json = serde_json::to_string(&request)?;
+        prop_assert_eq!(parse(&json)?, request);
+    }
+
+    #[test]
+    fn generated_messages_round_trip(message in chat_message()) {
+        let json = serde_json::to_string(&message)?;
+        prop_assert_eq!(serde_json::from_str::<ChatMessage>(&json)?, message);
+    }
+
+    #[test]
+    fn generated_references_round_trip(reference in copilot_reference()) {
+        let json = serde_json::to_string(&reference)?;
+        prop_assert_eq!(serde_json::from_str::<CopilotReference>(&json)?, reference);
+    }
+
+    #[test]
+    fn arbitrary_references_parse_stably(references in prop::collection::vec(arbitrary_reference(), 0..4)) {
+        let request = parse(&request_with_references(references.clone()))?;
+        prop_assert_eq!(request.messages[0].copilot_references.len(), references.len());
+        let json = serde_json::to_string(&request)?;
+        prop_assert_eq!(parse(&json)?, request);
+    }
+
+    #[test]
+    fn arbitrary_input_does_not_panic(input in "\\PC{0,64}", value in json_value()) {
+        let _ = ChatRequest::parse(&input);
+        let _ = ChatRequest::parse(&value.to_string());
+        let _ = ChatRequest::parse(&request_with_references(vec![value]));
+    }
+}
+
+#[test]
+fn samples_keep_parsing_and_round_trip() -> anyhow::Result<()> {
+    let mut samples = 0;
+    for entry in std::fs::read_dir("samples")? {
+        let path = entry?.path();
+        if path.extension() != Some("json".as_ref()) {
+            continue;
+        }
+        let payload = std::fs::read_to_string(&path)?;
+        let request =
+            ChatRequest::parse(&payload).map_err(|err| err.context(path.display().to_string()))?;
+        let json = serde_json::to_string(&request)?;
+        assert_eq!(ChatRequest::parse(&json)?, request, "{}", path.display());
+        samples += 1;
+    }
+    assert!(samples > 0);
+    Ok(())
+}
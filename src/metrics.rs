@@ -0,0 +1,343 @@
+use crate::state::AppState;
+use axum::extract::State;
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use prometheus_client::encoding::text::encode;
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::Registry;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, warn};
+
+/// Copilot integration ids labelling agent requests, any other one being counted as `other`
+/// so that clients cannot grow the number of series.
+const KNOWN_INTEGRATION_IDS: [&str; 3] = ["vscode-chat", "jetbrains-chat", "copilot-chat"];
+
+/// Counters and histograms exposed in the Prometheus format on `/metrics`.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Arc<Registry>,
+    agent_requests: Family<IntegrationLabels, Counter>,
+    signature_failures: Family<ReasonLabels, Counter>,
+    parse_failures: Counter,
+    oauth_exchanges: Family<OAuthExchangeLabels, Counter>,
+    key_refreshes: Family<OutcomeLabels, Counter>,
+    llm_latency: Family<OutcomeLabels, Histogram, fn() -> Histogram>,
+    llm_streamed_tokens: Counter,
+    unknown_reference_types: Family<ReferenceTypeLabels, Counter>,
+    malformed_references: Family<ReferenceTypeLabels, Counter>,
+    unknown_fields: Family<PathLabels, Counter>,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct IntegrationLabels {
+    integration_id: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ReasonLabels {
+    reason: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct OAuthExchangeLabels {
+    grant: &'static str,
+    outcome: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ReferenceTypeLabels {
+    reference_type: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct PathLabels {
+    path: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct OutcomeLabels {
+    outcome: &'static str,
+}
+
+const fn outcome(success: bool) -> &'static str {
+    if success {
+        "success"
+    } else {
+        "failure"
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let mut registry = Registry::with_prefix("toddler");
+        let agent_requests = Family::<IntegrationLabels, Counter>::default();
+        registry.register(
+            "agent_requests",
+            "Agent requests with a valid signature, by Copilot integration id",
+            agent_requests.clone(),
+        );
+        let signature_failures = Family::<ReasonLabels, Counter>::default();
+        registry.register(
+            "signature_failures",
+            "Agent requests rejected because of their signature, by reason",
+            signature_failures.clone(),
+        );
+        let parse_failures = Counter::default();
+        registry.register(
+            "parse_failures",
+            "Signed agent requests whose body could not be parsed",
+            parse_failures.clone(),
+        );
+        let oauth_exchanges = Family::<OAuthExchangeLabels, Counter>::default();
+        registry.register(
+            "oauth_exchanges",
+            "Exchanges of OAuth codes for tokens, by grant and outcome",
+            oauth_exchanges.clone(),
+        );
+        let key_refreshes = Family::<OutcomeLabels, Counter>::default();
+        registry.register(
+            "copilot_key_refreshes",
+            "Downloads of the Copilot public keys, by outcome",
+            key_refreshes.clone(),
+        );
+        let llm_latency =
+            Family::<OutcomeLabels, Histogram, fn() -> Histogram>::new_with_constructor(|| {
+                Histogram::new(exponential_buckets(0.1, 2.0, 12))
+            });
+        registry.register(
+            "llm_latency_seconds",
+            "Time for the Copilot API to stream a completion entirely, by outcome",
+            llm_latency.clone(),
+        );
+        let llm_streamed_tokens = Counter::default();
+        registry.register(
+            "llm_streamed_tokens",
+            "Tokens streamed back from the Copilot API, counted as content deltas",
+            llm_streamed_tokens.clone(),
+        );
+        let unknown_reference_types = Family::<ReferenceTypeLabels, Counter>::default();
+        registry.register(
+            "unknown_reference_types",
+            "References of a type the extension does not know, by type",
+            unknown_reference_types.clone(),
+        );
+        let malformed_references = Family::<ReferenceTypeLabels, Counter>::default();
+        registry.register(
+            "malformed_references",
+            "References not matching the shape expected for their type, by type",
+            malformed_references.clone(),
+        );
+        let unknown_fields = Family::<PathLabels, Counter>::default();
+        registry.register(
+            "unknown_fields",
+            "Fields of agent requests ignored by the extension, by path",
+            unknown_fields.clone(),
+        );
+        Self {
+            registry: Arc::new(registry),
+            agent_requests,
+            signature_failures,
+            parse_failures,
+            oauth_exchanges,
+            key_refreshes,
+            llm_latency,
+            llm_streamed_tokens,
+            unknown_reference_types,
+            malformed_references,
+            unknown_fields,
+        }
+    }
+}
+
+impl Metrics {
+    pub fn agent_request(&self, integration_id: Option<&str>) {
+        let integration_id = match integration_id {
+            Some(id) if KNOWN_INTEGRATION_IDS.contains(&id) => id,
+            Some(_) => "other",
+            None => "unknown",
+        };
+        self.agent_requests
+            .get_or_create(&IntegrationLabels {
+                integration_id: integration_id.to_string(),
+            })
+            .inc();
+    }
+
+    pub fn signature_failure(&self, reason: &'static str) {
+        self.signature_failures
+            .get_or_create(&ReasonLabels { reason })
+            .inc();
+    }
+
+    pub fn parse_failure(&self) {
+        self.parse_failures.inc();
+    }
+
+    pub fn oauth_exchange(&self, grant: &'static str, success: bool) {
+        self.oauth_exchanges
+            .get_or_create(&OAuthExchangeLabels {
+                grant,
+                outcome: outcome(success),
+            })
+            .inc();
+    }
+
+    pub fn key_refresh(&self, success: bool) {
+        self.key_refreshes
+            .get_or_create(&OutcomeLabels {
+                outcome: outcome(success),
+            })
+            .inc();
+    }
+
+    /// Records a completion once it is entirely streamed, or failed.
+    pub fn llm_latency(&self, latency: Duration, success: bool) {
+        self.llm_latency
+            .get_or_create(&OutcomeLabels {
+                outcome: outcome(success),
+            })
+            .observe(latency.as_secs_f64());
+    }
+
+    pub fn llm_streamed_tokens(&self, tokens: u64) {
+        self.llm_streamed_tokens.inc_by(tokens);
+    }
+
+    pub fn unknown_reference_type(&self, reference_type: &str) {
+        self.unknown_reference_types
+            .get_or_create(&ReferenceTypeLabels {
+                reference_type: reference_type.to_string(),
+            })
+            .inc();
+    }
+
+    pub fn malformed_reference(&self, reference_type: &str) {
+        self.malformed_references
+            .get_or_create(&ReferenceTypeLabels {
+                reference_type: reference_type.to_string(),
+            })
+            .inc();
+    }
+
+    pub fn unknown_field(&self, path: &str) {
+        self.unknown_fields
+            .get_or_create(&PathLabels {
+                path: path.to_string(),
+            })
+            .inc();
+    }
+
+    /// Renders every metric in the `OpenMetrics` text format.
+    pub fn encode(&self) -> Result<String, std::fmt::Error> {
+        let mut buffer = String::new();
+        encode(&mut buffer, &self.registry)?;
+        Ok(buffer)
+    }
+}
+
+/// Tells if the request carries the `METRICS_TOKEN` as bearer token, when one is configured.
+pub(crate) fn is_authorized(state: &AppState, headers: &HeaderMap) -> bool {
+    let Some(token) = state.config.metrics_token.as_deref() else {
+        return true;
+    };
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|provided| constant_time_eq(provided.as_bytes(), token.as_bytes()))
+}
+
+/// Checks the `METRICS_TOKEN` of a request to a `/debug` endpoint, answering 404 when no token is configured
+/// as these endpoints are never public.
+pub(crate) fn debug_authorization(state: &AppState, headers: &HeaderMap) -> Result<(), StatusCode> {
+    if state.config.metrics_token.is_none() {
+        Err(StatusCode::NOT_FOUND)
+    } else if is_authorized(state, headers) {
+        Ok(())
+    } else {
+        Err(StatusCode::UNAUTHORIZED)
+    }
+}
+
+/// Exposes the metrics, behind a bearer token when `METRICS_TOKEN` is configured.
+#[allow(clippy::unused_async)]
+pub async fn metrics(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if !is_authorized(&state, &headers) {
+        warn!("[http] metrics: Missing or invalid bearer token");
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    match state.metrics.encode() {
+        Ok(body) => (
+            [(
+                CONTENT_TYPE,
+                "application/openmetrics-text; version=1.0.0; charset=utf-8",
+            )],
+            body,
+        )
+            .into_response(),
+        Err(err) => {
+            error!(error = ?err, "[http] metrics: Unable to encode metrics");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Compares secrets without leaking the length of their common prefix through timing.
+fn constant_time_eq(left: &[u8], right: &[u8]) -> bool {
+    left.len() == right.len()
+        && left
+            .iter()
+            .zip(right)
+            .fold(0, |difference, (l, r)| difference | (l ^ r))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics::{constant_time_eq, Metrics};
+    use std::time::Duration;
+
+    #[test]
+    fn metrics_are_encoded_with_their_labels() -> anyhow::Result<()> {
+        let metrics = Metrics::default();
+        metrics.agent_request(Some("vscode-chat"));
+        metrics.agent_request(Some("vscode-chat"));
+        metrics.agent_request(None);
+        metrics.agent_request(Some("made-up-1"));
+        metrics.agent_request(Some("made-up-2"));
+        metrics.signature_failure("mismatch");
+        metrics.parse_failure();
+        metrics.oauth_exchange("device_code", false);
+        metrics.key_refresh(true);
+        metrics.llm_latency(Duration::from_millis(120), true);
+        metrics.llm_streamed_tokens(42);
+
+        let encoded = metrics.encode()?;
+        for expected in [
+            r#"toddler_agent_requests_total{integration_id="vscode-chat"} 2"#,
+            r#"toddler_agent_requests_total{integration_id="unknown"} 1"#,
+            r#"toddler_agent_requests_total{integration_id="other"} 2"#,
+            r#"toddler_signature_failures_total{reason="mismatch"} 1"#,
+            "toddler_parse_failures_total 1",
+            r#"toddler_oauth_exchanges_total{grant="device_code",outcome="failure"} 1"#,
+            r#"toddler_copilot_key_refreshes_total{outcome="success"} 1"#,
+            r#"toddler_llm_latency_seconds_count{outcome="success"} 1"#,
+            "toddler_llm_streamed_tokens_total 42",
+        ] {
+            assert!(encoded.contains(expected), "{expected} not in {encoded}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn tokens_are_compared_entirely() {
+        assert!(constant_time_eq(b"s3cr3t", b"s3cr3t"));
+        assert!(!constant_time_eq(b"s3cr3t", b"s3cr3T"));
+        assert!(!constant_time_eq(b"s3cr3t", b"s3cr3t-and-more"));
+    }
+}
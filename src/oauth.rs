@@ -1,19 +1,32 @@
+use crate::config::Config;
+use crate::github::GithubUser;
 use crate::state::AppState;
+use crate::token_store::StoredToken;
 use axum::extract::{Query, State};
-use axum::http::StatusCode;
-use axum::response::Redirect;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{Html, Redirect};
 use axum_extra::extract::cookie::{Cookie, SameSite};
 use axum_extra::extract::PrivateCookieJar;
+use oauth2::basic::{BasicClient, BasicTokenResponse};
 use oauth2::reqwest::async_http_client;
-use oauth2::{AuthorizationCode, CsrfToken, Scope};
-use time::Duration;
-use tracing::error;
+use oauth2::url::Url;
+use oauth2::{AuthorizationCode, CsrfToken, RefreshToken, Scope, TokenResponse};
+use time::{Duration, OffsetDateTime};
+use tracing::{error, info, info_span, warn, Instrument};
 
-const GH_STATE_COOKIE: &str = "gh_state";
+pub(crate) const GH_STATE_COOKIE: &str = "gh_state";
+pub(crate) const GH_RETURN_TO_COOKIE: &str = "gh_return_to";
+pub(crate) const GH_SESSION_COOKIE: &str = "gh_session";
 const GH_STATE_COOKIE_DURATION: Duration = Duration::minutes(10);
 
+#[derive(Debug, serde::Deserialize)]
+pub struct PreAuthRequest {
+    return_to: Option<String>,
+}
+
 #[allow(clippy::unused_async)]
 pub async fn pre_auth(
+    Query(query): Query<PreAuthRequest>,
     State(state): State<AppState>,
     jar: PrivateCookieJar,
 ) -> Result<(PrivateCookieJar, Redirect), StatusCode> {
@@ -24,15 +37,47 @@ pub async fn pre_auth(
         .add_scope(Scope::new("user:email".to_string()))
         .url();
 
-    Ok((
-        jar.add(
-            Cookie::build((GH_STATE_COOKIE, csrf_state.secret().clone()))
-                .path("/")
-                .max_age(GH_STATE_COOKIE_DURATION)
-                .same_site(SameSite::Lax),
-        ),
-        Redirect::to(authorize_url.as_ref()),
-    ))
+    let mut jar = jar.add(short_lived_cookie(
+        GH_STATE_COOKIE,
+        csrf_state.secret().clone(),
+    ));
+    if let Some(return_to) = query
+        .return_to
+        .as_deref()
+        .and_then(|return_to| parse_return_to(&state.config, return_to))
+    {
+        jar = jar.add(short_lived_cookie(GH_RETURN_TO_COOKIE, return_to.into()));
+    }
+
+    Ok((jar, Redirect::to(authorize_url.as_ref())))
+}
+
+fn short_lived_cookie(name: &'static str, value: String) -> Cookie<'static> {
+    Cookie::build((name, value))
+        .path("/")
+        .max_age(GH_STATE_COOKIE_DURATION)
+        .same_site(SameSite::Lax)
+        .build()
+}
+
+/// Only absolute http(s) URLs on the hosts of the GitHub web URL or of `BASE_URL`, or their subdomains,
+/// are accepted as `return_to`, anything else is ignored so that the app cannot redirect to any site.
+fn parse_return_to(config: &Config, raw: &str) -> Option<Url> {
+    let allowed_hosts: Vec<String> = [config.github.web_url.as_str(), config.base_url.as_str()]
+        .into_iter()
+        .filter_map(|allowed| Url::parse(allowed).ok()?.host_str().map(String::from))
+        .collect();
+    Url::parse(raw).ok().filter(|url| {
+        matches!(url.scheme(), "http" | "https")
+            && url.host_str().is_some_and(|host| {
+                allowed_hosts.iter().any(|allowed| {
+                    host == allowed
+                        || host
+                            .strip_suffix(allowed.as_str())
+                            .is_some_and(|subdomain| subdomain.ends_with('.'))
+                })
+            })
+    })
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -44,44 +89,203 @@ pub struct AuthRequest {
 pub async fn post_auth(
     Query(query): Query<AuthRequest>,
     State(state): State<AppState>,
+    headers: HeaderMap,
     mut jar: PrivateCookieJar,
-) -> (StatusCode, PrivateCookieJar, String) {
-    let state_check = check_state(&query, jar);
+) -> (StatusCode, PrivateCookieJar, Html<String>) {
+    let state_check = check_state(&state, &query, &headers, jar);
     jar = state_check.0;
     if state_check.1.is_err() {
         return (
             StatusCode::BAD_REQUEST,
             jar,
-            "state cookie missing or invalid".to_string(),
+            Html(error_page("state cookie missing or invalid")),
         );
     }
 
+    let return_to = state
+        .cookie_keys
+        .get(&jar, &headers, GH_RETURN_TO_COOKIE)
+        .and_then(|cookie| parse_return_to(&state.config, cookie.value()));
+    jar = jar.remove(Cookie::from(GH_RETURN_TO_COOKIE));
+
     let token_res = state
         .oauth_gh_client
         .exchange_code(AuthorizationCode::new(query.code.clone()))
         .request_async(async_http_client)
+        .instrument(info_span!("oauth_exchange", grant = "authorization_code"))
         .await;
+    state
+        .metrics
+        .oauth_exchange("authorization_code", token_res.is_ok());
 
-    if let Err(err) = token_res {
-        error!(error = ?err, "[http] post_auth");
-        (
-            StatusCode::BAD_REQUEST,
-            jar,
-            format!("error exchanging code for token {err:#?}"),
-        )
-    } else {
-        (
-            StatusCode::OK,
-            jar,
-            "All done! Please return to the app".to_string(),
-        )
+    match token_res {
+        Err(err) => {
+            error!(error = ?err, "[http] post_auth");
+            (
+                StatusCode::BAD_REQUEST,
+                jar,
+                Html(error_page("error exchanging code for token")),
+            )
+        }
+        Ok(token) => match store_token(&state, &token).await {
+            Err(err) => {
+                error!(error = ?err, "[http] post_auth: Unable to identify the user");
+                (
+                    StatusCode::BAD_GATEWAY,
+                    jar,
+                    Html(error_page("unable to identify your GitHub account")),
+                )
+            }
+            Ok(user) => (
+                StatusCode::OK,
+                jar.add(
+                    Cookie::build((GH_SESSION_COOKIE, user.id.to_string()))
+                        .path("/")
+                        .http_only(true)
+                        .secure(true)
+                        .same_site(SameSite::Lax),
+                ),
+                Html(success_page(&user.login, return_to.as_ref())),
+            ),
+        },
+    }
+}
+
+/// Stores the token obtained at the end of the OAuth flow, returning its owner.
+pub async fn store_token(
+    state: &AppState,
+    token: &BasicTokenResponse,
+) -> anyhow::Result<GithubUser> {
+    let user = state
+        .github_client
+        .user(token.access_token().secret())
+        .await?;
+    state
+        .token_store
+        .insert(user.id, stored_token(&user.login, token));
+    info!(
+        user_id = user.id,
+        login = user.login,
+        "[http] post_auth: Account connected"
+    );
+    Ok(user)
+}
+
+/// Stored token of the user, refreshed with its refresh token once expired, `None` when there is none
+/// or when it cannot be refreshed.
+pub async fn valid_token(state: &AppState, user_id: u64) -> Option<StoredToken> {
+    if let Some(token) = state.token_store.get_valid(user_id) {
+        return Some(token);
+    }
+    let expired = state.token_store.get(user_id)?;
+    let refresh_token = expired.refresh_token?;
+    let refreshed = refresh(&state.oauth_gh_client, &expired.login, refresh_token)
+        .instrument(info_span!("oauth_exchange", grant = "refresh_token"))
+        .await;
+    state
+        .metrics
+        .oauth_exchange("refresh_token", refreshed.is_ok());
+    match refreshed {
+        Ok(token) => {
+            state.token_store.insert(user_id, token.clone());
+            Some(token)
+        }
+        Err(err) => {
+            warn!(user_id, error = ?err, "[http] valid_token: Unable to refresh the token");
+            None
+        }
+    }
+}
+
+async fn refresh(
+    client: &BasicClient,
+    login: &str,
+    refresh_token: String,
+) -> anyhow::Result<StoredToken> {
+    let token = client
+        .exchange_refresh_token(&RefreshToken::new(refresh_token))
+        .request_async(async_http_client)
+        .await?;
+    Ok(stored_token(login, &token))
+}
+
+fn stored_token(login: &str, token: &BasicTokenResponse) -> StoredToken {
+    StoredToken {
+        login: login.to_string(),
+        access_token: token.access_token().secret().clone(),
+        refresh_token: token.refresh_token().map(|t| t.secret().clone()),
+        expires_at: token.expires_in().and_then(|expires_in| {
+            Duration::try_from(expires_in)
+                .ok()
+                .map(|expires_in| OffsetDateTime::now_utc() + expires_in)
+        }),
     }
 }
 
-fn check_state(query: &AuthRequest, jar: PrivateCookieJar) -> (PrivateCookieJar, Result<(), ()>) {
+fn success_page(login: &str, return_to: Option<&Url>) -> String {
+    let next_step = return_to.map_or_else(
+        || "<p>You can close this window and return to Copilot Chat.</p>".to_string(),
+        |url| {
+            format!(
+                r#"<p><a href="{}">Return to Copilot Chat</a></p>"#,
+                escape_html(url.as_str())
+            )
+        },
+    );
+    page(
+        "Account connected",
+        &format!(
+            "<h1>All done!</h1><p>Your GitHub account <strong>{}</strong> is now connected.</p>{next_step}",
+            escape_html(login)
+        ),
+    )
+}
+
+fn error_page(message: &str) -> String {
+    page(
+        "Connection failed",
+        &format!(
+            "<h1>Connection failed</h1><p>{}, please try again.</p>",
+            escape_html(message)
+        ),
+    )
+}
+
+fn page(title: &str, body: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="utf-8">
+  <title>{title}</title>
+  <style>body {{ font-family: -apple-system, "Segoe UI", Helvetica, Arial, sans-serif; max-width: 40em; margin: 4em auto; }}</style>
+</head>
+<body>
+{body}
+</body>
+</html>
+"#
+    )
+}
+
+fn escape_html(raw: &str) -> String {
+    raw.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn check_state(
+    state: &AppState,
+    query: &AuthRequest,
+    headers: &HeaderMap,
+    jar: PrivateCookieJar,
+) -> (PrivateCookieJar, Result<(), ()>) {
     let state_token = CsrfToken::new(query.state.clone());
-    let stored_secret: Option<String> = jar
-        .get(GH_STATE_COOKIE)
+    let stored_secret: Option<String> = state
+        .cookie_keys
+        .get(&jar, headers, GH_STATE_COOKIE)
         .map(|cookie| cookie.value().to_owned());
 
     let jar = jar.remove(Cookie::from(GH_STATE_COOKIE));
@@ -105,3 +309,95 @@ fn check_state(query: &AuthRequest, jar: PrivateCookieJar) -> (PrivateCookieJar,
         (jar, Ok(()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::oauth::{parse_return_to, refresh, success_page};
+    use axum::routing::post;
+    use axum::{Form, Json, Router};
+    use oauth2::basic::BasicClient;
+    use oauth2::{AuthUrl, ClientId, TokenUrl};
+    use std::collections::HashMap;
+
+    fn config() -> anyhow::Result<Config> {
+        Config::try_from(HashMap::from([
+            (
+                "BASE_URL".to_string(),
+                "https://toddler.example.com".to_string(),
+            ),
+            ("GITHUB_APP_CLIENT_ID".to_string(), "Iv1.abc".to_string()),
+            ("GITHUB_APP_CLIENT_SECRET".to_string(), "secret".to_string()),
+        ]))
+    }
+
+    #[test]
+    fn return_to_only_accepts_http_urls() -> anyhow::Result<()> {
+        let config = config()?;
+        assert!(parse_return_to(&config, "https://github.com/copilot").is_some());
+        assert!(parse_return_to(&config, "javascript:alert(1)").is_none());
+        assert!(parse_return_to(&config, "/relative").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn return_to_only_accepts_github_and_the_app() -> anyhow::Result<()> {
+        let config = config()?;
+        assert!(parse_return_to(&config, "https://toddler.example.com/done").is_some());
+        assert!(parse_return_to(&config, "https://gist.github.com/ledoyen").is_some());
+        assert!(parse_return_to(&config, "https://evil.example.com/login").is_none());
+        assert!(parse_return_to(&config, "https://notgithub.com/copilot").is_none());
+        assert!(parse_return_to(&config, "https://github.com.evil.example.com").is_none());
+        assert!(parse_return_to(&config, "https://github.com@evil.example.com").is_none());
+        Ok(())
+    }
+
+    async fn mock_token_endpoint(
+        Form(form): Form<HashMap<String, String>>,
+    ) -> Json<serde_json::Value> {
+        Json(
+            match (form["grant_type"].as_str(), form["refresh_token"].as_str()) {
+                ("refresh_token", "ghr_valid") => serde_json::json!({
+                    "access_token": "ghu_refreshed", "token_type": "bearer", "expires_in": 28800,
+                    "refresh_token": "ghr_next"
+                }),
+                _ => serde_json::json!({ "error": "bad_refresh_token" }),
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn expired_tokens_are_refreshed() -> anyhow::Result<()> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let base_url = format!("http://{}", listener.local_addr()?);
+        let router = Router::new().route("/login/oauth/access_token", post(mock_token_endpoint));
+        tokio::spawn(async move { axum::serve(listener, router).await });
+        let client = BasicClient::new(
+            ClientId::new("client-id".to_string()),
+            None,
+            AuthUrl::new(format!("{base_url}/login/oauth/authorize"))?,
+            Some(TokenUrl::new(format!(
+                "{base_url}/login/oauth/access_token"
+            ))?),
+        );
+
+        let token = refresh(&client, "ledoyen", "ghr_valid".to_string()).await?;
+        assert_eq!(token.login, "ledoyen");
+        assert_eq!(token.access_token, "ghu_refreshed");
+        assert_eq!(token.refresh_token.as_deref(), Some("ghr_next"));
+        assert!(!token.is_expired());
+        assert!(refresh(&client, "ledoyen", "ghr_revoked".to_string())
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn success_page_escapes_user_content() -> anyhow::Result<()> {
+        let return_to = parse_return_to(&config()?, "https://github.com/copilot?a=1&b=\"2\"");
+        let page = success_page("<script>", return_to.as_ref());
+        assert!(page.contains("&lt;script&gt;"));
+        assert!(page.contains(r#"href="https://github.com/copilot?a=1&amp;b=%222%22""#));
+        Ok(())
+    }
+}
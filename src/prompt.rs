@@ -0,0 +1,765 @@
+use crate::conversation::{Conversation, Turn, TurnRole};
+use crate::llm::LlmMessage;
+use crate::messages::{ClientFile, ClientSelection, CopilotReference, CopilotReferenceData};
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+use std::sync::OnceLock;
+use tiktoken_rs::CoreBPE;
+use tracing::error;
+
+/// Tokens added by the chat format around the content of each message.
+const MESSAGE_OVERHEAD_TOKENS: usize = 4;
+/// Share of the budget left by the system prompt and the last turn that references may take, in percent.
+const REFERENCES_SHARE_PERCENT: usize = 50;
+
+const REFERENCES_HEADER: &str = "Context provided by the user:";
+
+const TRUNCATION_MARKER: &str = "\n\n[truncated]";
+
+/// Encoding of the GPT-4o models, bundled in the binary so that tokens are counted offline.
+fn encoding() -> Option<&'static CoreBPE> {
+    static ENCODING: OnceLock<Option<CoreBPE>> = OnceLock::new();
+    ENCODING
+        .get_or_init(|| {
+            tiktoken_rs::o200k_base()
+                .map_err(|err| error!(error = ?err, "[prompt] Unable to load the encoding, estimating tokens from lengths"))
+                .ok()
+        })
+        .as_ref()
+}
+
+/// Number of tokens of a text, estimated from its length if the encoding could not be loaded.
+#[must_use]
+pub fn count_tokens(text: &str) -> usize {
+    encoding().map_or_else(
+        || text.chars().count().div_ceil(4),
+        |encoding| encoding.encode_ordinary(text).len(),
+    )
+}
+
+/// Builds the messages sent to the model from a conversation, within a budget of tokens.
+///
+/// The system prompt, the session context and the last turn are always sent, the end of the last turn being cut
+/// when it does not fit.
+/// References, newest first, take up to half of what is left, large files being windowed around their selection,
+/// sent in a user message before the last turn,
+/// then the history takes the rest, the oldest turns being dropped first.
+pub struct PromptBuilder {
+    max_tokens: usize,
+    system: Option<String>,
+}
+
+/// Messages to send to the model, with what was left out to fit the budget.
+#[derive(Debug)]
+pub struct Prompt {
+    pub messages: Vec<LlmMessage>,
+    pub tokens: usize,
+    pub dropped: Vec<Dropped>,
+}
+
+/// Error of a system prompt and session context leaving no room for the conversation in the budget.
+#[derive(Clone, Copy, Debug)]
+pub struct PromptTooLarge {
+    pub system_tokens: usize,
+    pub max_tokens: usize,
+}
+
+impl Display for PromptTooLarge {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the system prompt takes {} tokens, leaving no room for the conversation in {} tokens",
+            self.system_tokens, self.max_tokens
+        )
+    }
+}
+
+impl std::error::Error for PromptTooLarge {}
+
+/// Part of the conversation left out of a prompt.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Dropped {
+    /// End of the last turn, of which only the given number of characters were sent
+    LastTurnTruncated {
+        kept_chars: usize,
+        total_chars: usize,
+    },
+    /// Oldest turns of the history
+    Turns(usize),
+    /// Reference which did not fit at all
+    Reference { id: String },
+    /// File of which only some lines were sent, counted from 1
+    FileWindowed {
+        id: String,
+        start_line: usize,
+        end_line: usize,
+        total_lines: usize,
+    },
+}
+
+impl Display for Dropped {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::LastTurnTruncated {
+                kept_chars,
+                total_chars,
+            } => write!(
+                f,
+                "the end of the last message, after {kept_chars} characters out of {total_chars}"
+            ),
+            Self::Turns(1) => f.write_str("the oldest message of the conversation"),
+            Self::Turns(count) => write!(f, "the {count} oldest messages of the conversation"),
+            Self::Reference { id } => write!(f, "`{id}`"),
+            Self::FileWindowed {
+                id,
+                start_line,
+                end_line,
+                total_lines,
+            } => write!(
+                f,
+                "the lines of `{id}` outside of {start_line}-{end_line} (out of {total_lines})"
+            ),
+        }
+    }
+}
+
+impl PromptBuilder {
+    #[must_use]
+    pub const fn new(max_tokens: usize) -> Self {
+        Self {
+            max_tokens,
+            system: None,
+        }
+    }
+
+    /// Instructions sent first, before the session context.
+    #[must_use]
+    pub fn system(mut self, system: impl Into<String>) -> Self {
+        self.system = Some(system.into());
+        self
+    }
+
+    /// Fails when the system prompt and session context take the whole budget.
+    pub fn build(&self, conversation: &Conversation) -> Result<Prompt, PromptTooLarge> {
+        let mut dropped = vec![];
+        let system = self
+            .system
+            .as_deref()
+            .into_iter()
+            .chain(conversation.session.context.iter().copied())
+            .collect::<Vec<_>>()
+            .join("\n");
+        let system = (!system.is_empty()).then(|| llm_message("system", system));
+        let system_tokens: usize = system.iter().map(message_tokens).sum();
+        let too_large = PromptTooLarge {
+            system_tokens,
+            max_tokens: self.max_tokens,
+        };
+        let (last, history) = match conversation.turns.split_last() {
+            Some((last, history)) => {
+                let available = self
+                    .max_tokens
+                    .checked_sub(system_tokens)
+                    .ok_or(too_large)?;
+                let last =
+                    fit_message(turn_message(last), available, &mut dropped).ok_or(too_large)?;
+                (Some(last), history)
+            }
+            None if system_tokens > self.max_tokens => return Err(too_large),
+            None => (None, &[][..]),
+        };
+        let mut tokens = system_tokens + last.iter().map(message_tokens).sum::<usize>();
+
+        let references_budget =
+            self.max_tokens.saturating_sub(tokens) * REFERENCES_SHARE_PERCENT / 100;
+        let references = references_message(conversation, references_budget, &mut dropped);
+        tokens += references.iter().map(message_tokens).sum::<usize>();
+
+        let mut kept = vec![];
+        for (index, turn) in history.iter().enumerate().rev() {
+            let message = turn_message(turn);
+            let message_tokens = message_tokens(&message);
+            if tokens + message_tokens > self.max_tokens {
+                dropped.push(Dropped::Turns(index + 1));
+                break;
+            }
+            tokens += message_tokens;
+            kept.push(message);
+        }
+        kept.reverse();
+
+        Ok(Prompt {
+            messages: system
+                .into_iter()
+                .chain(kept)
+                .chain(references)
+                .chain(last)
+                .collect(),
+            tokens,
+            dropped,
+        })
+    }
+}
+
+/// Message cut to fit the budget, keeping its beginning, or `None` if not even the truncation marker fits.
+fn fit_message(
+    mut message: LlmMessage,
+    budget: usize,
+    dropped: &mut Vec<Dropped>,
+) -> Option<LlmMessage> {
+    if message_tokens(&message) <= budget {
+        return Some(message);
+    }
+    let boundaries: Vec<usize> = message
+        .content
+        .char_indices()
+        .map(|(index, _)| index)
+        .chain([message.content.len()])
+        .collect();
+    let fits = |chars: usize| {
+        let truncated = format!(
+            "{}{TRUNCATION_MARKER}",
+            &message.content[..boundaries[chars]]
+        );
+        count_tokens(&truncated) + MESSAGE_OVERHEAD_TOKENS <= budget
+    };
+    if !fits(0) {
+        return None;
+    }
+    // tokens grow with the length of the prefix, give or take merges at the cut, so the longest prefix is bisected
+    let (mut kept_chars, mut too_many_chars) = (0, boundaries.len() - 1);
+    while too_many_chars - kept_chars > 1 {
+        let middle = kept_chars + (too_many_chars - kept_chars) / 2;
+        if fits(middle) {
+            kept_chars = middle;
+        } else {
+            too_many_chars = middle;
+        }
+    }
+    dropped.push(Dropped::LastTurnTruncated {
+        kept_chars,
+        total_chars: boundaries.len() - 1,
+    });
+    message.content.truncate(boundaries[kept_chars]);
+    message.content.push_str(TRUNCATION_MARKER);
+    Some(message)
+}
+
+fn llm_message(role: &str, content: String) -> LlmMessage {
+    LlmMessage {
+        role: role.to_string(),
+        content,
+    }
+}
+
+fn turn_message(turn: &Turn) -> LlmMessage {
+    let role = match turn.role {
+        TurnRole::User => "user",
+        TurnRole::Assistant => "assistant",
+    };
+    let tool_results = turn
+        .recorded
+        .map(|entry| entry.tool_results.as_slice())
+        .unwrap_or_default();
+    if tool_results.is_empty() {
+        return llm_message(role, turn.content.to_string());
+    }
+    let results = tool_results
+        .iter()
+        .map(|tool_result| format!("Result of `{}`: {}", tool_result.name, tool_result.result))
+        .collect::<Vec<_>>()
+        .join("\n");
+    llm_message(role, format!("{}\n\n{results}", turn.content))
+}
+
+fn message_tokens(message: &LlmMessage) -> usize {
+    count_tokens(&message.content) + MESSAGE_OVERHEAD_TOKENS
+}
+
+/// References of the conversation, newest first, each sent once.
+fn distinct_references<'a>(conversation: &Conversation<'a>) -> Vec<&'a CopilotReference> {
+    let mut seen = HashSet::new();
+    conversation
+        .turns
+        .iter()
+        .rev()
+        .flat_map(|turn| turn.references.iter().rev())
+        .chain(conversation.session.references.iter().rev())
+        .copied()
+        .filter(|reference| reference_key(reference).is_some_and(|key| seen.insert(key)))
+        .collect()
+}
+
+fn reference_key(reference: &CopilotReference) -> Option<(&'static str, &str)> {
+    match reference {
+        CopilotReference::GithubRepository(reference) => Some(("repository", &reference.id)),
+        CopilotReference::ClientFile(reference) => Some(("file", &reference.id)),
+        CopilotReference::ClientSelection(reference) => Some(("selection", &reference.id)),
+        CopilotReference::GithubCurrentUrl(reference) => Some(("url", &reference.id)),
+        CopilotReference::Unknown(_) => None,
+    }
+}
+
+/// References fitting the budget, as a user message.
+fn references_message(
+    conversation: &Conversation,
+    budget: usize,
+    dropped: &mut Vec<Dropped>,
+) -> Option<LlmMessage> {
+    let references = distinct_references(conversation);
+    let mut remaining =
+        budget.saturating_sub(MESSAGE_OVERHEAD_TOKENS + count_tokens(REFERENCES_HEADER) + 1);
+    let mut blocks = vec![];
+    for reference in &references {
+        let Some((id, block)) = render_reference(reference) else {
+            continue;
+        };
+        // blocks are separated by a blank line
+        let tokens = count_tokens(&block) + 1;
+        if tokens <= remaining {
+            remaining -= tokens;
+            blocks.push(block);
+            continue;
+        }
+        let windowed = match reference {
+            CopilotReference::ClientFile(file) => {
+                window_file(file, selection_of(&references, &file.id), remaining)
+            }
+            _ => None,
+        };
+        match windowed {
+            Some((block, window, total_lines)) => {
+                remaining = remaining.saturating_sub(count_tokens(&block) + 1);
+                blocks.push(block);
+                dropped.push(Dropped::FileWindowed {
+                    id: id.to_string(),
+                    start_line: window.start + 1,
+                    end_line: window.end,
+                    total_lines,
+                });
+            }
+            None => dropped.push(Dropped::Reference { id: id.to_string() }),
+        }
+    }
+    (!blocks.is_empty()).then(|| {
+        llm_message(
+            "user",
+            format!("{REFERENCES_HEADER}\n\n{}", blocks.join("\n\n")),
+        )
+    })
+}
+
+fn render_reference(reference: &CopilotReference) -> Option<(&str, String)> {
+    match reference {
+        CopilotReference::GithubRepository(reference) => {
+            let repository = &reference.data;
+            let mut block = format!(
+                "Repository `{}/{}`",
+                repository.owner_login, repository.name
+            );
+            if let Some(git_ref) = repository.git_ref() {
+                block = format!("{block}, on `{git_ref}`");
+            }
+            if !repository.description.is_empty() {
+                block = format!("{block}: {}", repository.description);
+            }
+            Some((&reference.id, block))
+        }
+        CopilotReference::ClientFile(reference) => {
+            let lines: Vec<&str> = reference.data.content.lines().collect();
+            Some((&reference.id, file_block(reference, &lines, 0..lines.len())))
+        }
+        CopilotReference::ClientSelection(reference) => Some((
+            &reference.id,
+            format!(
+                "Selection in `{}`, lines {}-{}:\n```\n{}\n```",
+                reference.id,
+                reference.data.start.line,
+                reference.data.end.line,
+                reference.data.content
+            ),
+        )),
+        CopilotReference::GithubCurrentUrl(reference) => Some((
+            &reference.id,
+            format!("Current page: {}", reference.data.url),
+        )),
+        CopilotReference::Unknown(_) => None,
+    }
+}
+
+fn file_block(
+    file: &CopilotReferenceData<ClientFile>,
+    lines: &[&str],
+    window: Range<usize>,
+) -> String {
+    let header = if window == (0..lines.len()) {
+        format!("File `{}`:", file.id)
+    } else {
+        format!(
+            "File `{}`, lines {}-{} out of {}:",
+            file.id,
+            window.start + 1,
+            window.end,
+            lines.len()
+        )
+    };
+    format!(
+        "{header}\n```{}\n{}\n```",
+        file.data.language,
+        lines[window].join("\n")
+    )
+}
+
+/// Selection made in a file.
+fn selection_of<'a>(
+    references: &[&'a CopilotReference],
+    file_id: &str,
+) -> Option<&'a ClientSelection> {
+    references.iter().find_map(|reference| match reference {
+        CopilotReference::ClientSelection(selection)
+            if is_selection_file(file_id, &selection.id) =>
+        {
+            Some(&selection.data)
+        }
+        _ => None,
+    })
+}
+
+/// Tells if a selection, whose id is the name of the file, is made in the file, whose id may be its path or URL.
+pub(crate) fn is_selection_file(file_id: &str, selection_id: &str) -> bool {
+    file_id == selection_id || file_id.ends_with(&format!("/{selection_id}"))
+}
+
+/// Largest window of the file fitting the budget, grown around the selection, or from the head of the file,
+/// with the window and the number of lines of the file.
+fn window_file(
+    file: &CopilotReferenceData<ClientFile>,
+    selection: Option<&ClientSelection>,
+    budget: usize,
+) -> Option<(String, Range<usize>, usize)> {
+    let lines: Vec<&str> = file.data.content.lines().collect();
+    let total = lines.len();
+    let (mut start, mut end) = selection.map_or((0, 0), |selection| {
+        let start = (selection.start.line as usize).saturating_sub(1).min(total);
+        (start, (selection.end.line as usize).clamp(start, total))
+    });
+    // lines are counted apart, with their line break, which is close to but not exactly how the block is encoded
+    let line_tokens: Vec<usize> = lines.iter().map(|line| count_tokens(line) + 1).collect();
+    let mut used = count_tokens(&file_block(file, &lines, 0..0));
+    used += line_tokens[start..end].iter().sum::<usize>();
+    while used > budget && end > start {
+        end -= 1;
+        used -= line_tokens[end];
+    }
+    let anchor = start + (end - start) / 2;
+    loop {
+        let grows_down = end < total && used + line_tokens[end] <= budget;
+        if grows_down {
+            used += line_tokens[end];
+            end += 1;
+        }
+        let grows_up = start > 0 && used + line_tokens[start - 1] <= budget;
+        if grows_up {
+            start -= 1;
+            used += line_tokens[start];
+        }
+        if !grows_down && !grows_up {
+            break;
+        }
+    }
+    let mut block = file_block(file, &lines, start..end);
+    while count_tokens(&block) > budget && end > start {
+        if end - anchor > anchor - start {
+            end -= 1;
+        } else {
+            start += 1;
+        }
+        block = file_block(file, &lines, start..end);
+    }
+    (end > start).then_some((block, start..end, total))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conversation::{Conversation, Session, Turn, TurnRole};
+    use crate::messages::{
+        ChatRequest, ClientFile, ClientSelection, CopilotReference, CopilotReferenceData,
+        SelectionPosition,
+    };
+    use crate::prompt::{count_tokens, Dropped, PromptBuilder};
+    use crate::thread_store::{ThreadEntry, ToolResult};
+    use std::fs;
+
+    fn turn<'a>(
+        role: TurnRole,
+        content: &'a str,
+        references: Vec<&'a CopilotReference>,
+    ) -> Turn<'a> {
+        Turn {
+            role,
+            content,
+            references,
+            recorded: None,
+        }
+    }
+
+    fn file(id: &str, lines: usize) -> CopilotReference {
+        CopilotReference::ClientFile(CopilotReferenceData {
+            data: ClientFile {
+                content: (1..=lines)
+                    .map(|line| format!("let line_{line} = {line};"))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                language: "rust".to_string(),
+            },
+            id: id.to_string(),
+            ..CopilotReferenceData::default()
+        })
+    }
+
+    fn selection(id: &str, start_line: u32, end_line: u32) -> CopilotReference {
+        CopilotReference::ClientSelection(CopilotReferenceData {
+            data: ClientSelection {
+                content: "let line_500 = 500;".to_string(),
+                start: SelectionPosition {
+                    line: start_line,
+                    col: 1,
+                },
+                end: SelectionPosition {
+                    line: end_line,
+                    col: 20,
+                },
+            },
+            id: id.to_string(),
+            ..CopilotReferenceData::default()
+        })
+    }
+
+    #[test]
+    fn tokens_are_counted_with_the_bundled_encoding() {
+        assert_eq!(count_tokens("hello world"), 2);
+        assert_eq!(count_tokens(""), 0);
+    }
+
+    #[test]
+    fn prompt_is_built_from_the_normalized_conversation() -> anyhow::Result<()> {
+        let payload = fs::read_to_string("samples/chat_request_from_web_with_repository.json")?;
+        let prompt =
+            PromptBuilder::new(64_000).build(&ChatRequest::parse(&payload)?.conversation())?;
+        let messages: Vec<_> = prompt
+            .messages
+            .iter()
+            .map(|message| (message.role.as_str(), message.content.as_str()))
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                (
+                    "system",
+                    "Current Date and Time (UTC): 2024-12-09 18:48:35\nCurrent User's Login: ledoyen"
+                ),
+                ("user", "coucou"),
+                (
+                    "user",
+                    "Context provided by the user:\n\n\
+                     Repository `ledoyen/toddler-copilot-extension`, on `main`\n\n\
+                     Current page: https://github.com/ledoyen/toddler-copilot-extension/actions"
+                ),
+                ("user", "tuttut"),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn small_conversations_are_sent_whole() -> anyhow::Result<()> {
+        let payload = fs::read_to_string("samples/chat_request_from_vs_code_with_selection.json")?;
+        let request = ChatRequest::parse(&payload)?;
+        let prompt = PromptBuilder::new(64_000)
+            .system("You are a toddler.")
+            .build(&request.conversation())?;
+        assert_eq!(prompt.dropped, vec![]);
+        let roles: Vec<_> = prompt
+            .messages
+            .iter()
+            .map(|message| message.role.as_str())
+            .collect();
+        assert_eq!(roles, vec!["system", "user", "user", "user"]);
+        assert_eq!(prompt.messages[0].content, "You are a toddler.");
+        assert!(prompt.messages[2]
+            .content
+            .contains("Selection in `+page.svelte`, lines 44-51:"));
+        assert!(prompt.messages[2]
+            .content
+            .contains("Repository `korekto/korekto-frontend`"));
+        assert!(prompt.tokens <= 64_000);
+        Ok(())
+    }
+
+    #[test]
+    fn oldest_turns_are_dropped_first() -> anyhow::Result<()> {
+        let contents: Vec<String> = (0..20)
+            .map(|index| format!("message {index} {}", "blah ".repeat(50)))
+            .collect();
+        let conversation = Conversation {
+            session: Session::default(),
+            turns: contents
+                .iter()
+                .enumerate()
+                .map(|(index, content)| {
+                    let role = if index % 2 == 0 {
+                        TurnRole::User
+                    } else {
+                        TurnRole::Assistant
+                    };
+                    turn(role, content, vec![])
+                })
+                .collect(),
+        };
+        let prompt = PromptBuilder::new(500).build(&conversation)?;
+        assert!(prompt.tokens <= 500);
+        let Some(Dropped::Turns(dropped)) = prompt.dropped.first() else {
+            panic!("no turn dropped: {:?}", prompt.dropped);
+        };
+        assert_eq!(prompt.messages.len() + dropped, 20);
+        assert_eq!(prompt.messages[0].content, contents[*dropped]);
+        assert_eq!(prompt.messages.last().map(|m| &m.content), contents.last());
+        Ok(())
+    }
+
+    #[test]
+    fn tool_results_of_recorded_answers_are_sent() -> anyhow::Result<()> {
+        let recorded = ThreadEntry {
+            tool_results: vec![ToolResult {
+                name: "search".to_string(),
+                result: serde_json::json!({ "hits": 2 }),
+            }],
+            ..ThreadEntry::default()
+        };
+        let mut answer = turn(TurnRole::Assistant, "Found it", vec![]);
+        answer.recorded = Some(&recorded);
+        let conversation = Conversation {
+            session: Session::default(),
+            turns: vec![turn(TurnRole::User, "search", vec![]), answer],
+        };
+        let prompt = PromptBuilder::new(500).build(&conversation)?;
+        assert_eq!(
+            prompt.messages[1].content,
+            "Found it\n\nResult of `search`: {\"hits\":2}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn large_files_are_windowed_around_the_selection() -> anyhow::Result<()> {
+        let file = file("file:///workspace/src/main.rs", 1_000);
+        let selection = selection("main.rs", 500, 502);
+        let conversation = Conversation {
+            session: Session::default(),
+            turns: vec![turn(TurnRole::User, "explain", vec![&file, &selection])],
+        };
+        let prompt = PromptBuilder::new(2_000).build(&conversation)?;
+        assert!(prompt.tokens <= 2_000);
+        let Some(Dropped::FileWindowed {
+            id,
+            start_line,
+            end_line,
+            total_lines,
+        }) = prompt.dropped.first()
+        else {
+            panic!("file not windowed: {:?}", prompt.dropped);
+        };
+        assert_eq!(id, "file:///workspace/src/main.rs");
+        assert_eq!(*total_lines, 1_000);
+        assert!(*start_line > 1 && *start_line <= 500);
+        assert!(*end_line >= 502 && *end_line < 1_000);
+        let references = &prompt.messages[0].content;
+        assert!(references.contains(&format!(
+            "File `file:///workspace/src/main.rs`, lines {start_line}-{end_line} out of 1000:"
+        )));
+        assert!(references.contains("let line_501 = 501;"));
+        assert!(!references.contains("let line_1 = 1;"));
+        Ok(())
+    }
+
+    #[test]
+    fn files_without_selection_are_windowed_from_their_head() -> anyhow::Result<()> {
+        let file = file("lib.rs", 1_000);
+        let conversation = Conversation {
+            session: Session::default(),
+            turns: vec![turn(TurnRole::User, "explain", vec![&file])],
+        };
+        let prompt = PromptBuilder::new(1_000).build(&conversation)?;
+        assert!(prompt.tokens <= 1_000);
+        let [Dropped::FileWindowed {
+            start_line: 1,
+            end_line,
+            ..
+        }] = prompt.dropped.as_slice()
+        else {
+            panic!("file not windowed from its head: {:?}", prompt.dropped);
+        };
+        assert_eq!(
+            prompt.dropped[0].to_string(),
+            format!("the lines of `lib.rs` outside of 1-{end_line} (out of 1000)")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn references_not_fitting_are_dropped() -> anyhow::Result<()> {
+        let file = file("lib.rs", 1_000);
+        let conversation = Conversation {
+            session: Session::default(),
+            turns: vec![turn(TurnRole::User, "explain", vec![&file])],
+        };
+        let prompt = PromptBuilder::new(40).build(&conversation)?;
+        assert_eq!(
+            prompt.dropped,
+            vec![Dropped::Reference {
+                id: "lib.rs".to_string()
+            }]
+        );
+        assert_eq!(prompt.messages.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn last_turn_is_truncated_to_fit() -> anyhow::Result<()> {
+        let question = format!("explain {}", "blah ".repeat(1_000));
+        let conversation = Conversation {
+            session: Session::default(),
+            turns: vec![turn(TurnRole::User, &question, vec![])],
+        };
+        let prompt = PromptBuilder::new(200)
+            .system("You are a toddler.")
+            .build(&conversation)?;
+        assert!(prompt.tokens <= 200, "{} tokens", prompt.tokens);
+        let [Dropped::LastTurnTruncated {
+            kept_chars,
+            total_chars,
+        }] = prompt.dropped.as_slice()
+        else {
+            panic!("last turn not truncated: {:?}", prompt.dropped);
+        };
+        assert_eq!(*total_chars, question.len());
+        assert!(*kept_chars > 0 && *kept_chars < *total_chars);
+        let sent = &prompt.messages[1].content;
+        assert!(sent.starts_with("explain blah"));
+        assert!(sent.ends_with("[truncated]"));
+        Ok(())
+    }
+
+    #[test]
+    fn system_prompt_taking_the_whole_budget_is_an_error() {
+        let conversation = Conversation {
+            session: Session::default(),
+            turns: vec![turn(TurnRole::User, "explain", vec![])],
+        };
+        let prompt = PromptBuilder::new(20)
+            .system("You are a toddler. ".repeat(10))
+            .build(&conversation);
+        assert!(prompt.is_err());
+    }
+}
@@ -0,0 +1,454 @@
+use crate::config::Config;
+use crate::messages::{ChatRequest, CopilotReference};
+use crate::metrics::debug_authorization;
+use crate::prompt::is_selection_file;
+use crate::state::AppState;
+use anyhow::Context;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use minijinja::{Environment, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError, RwLock};
+use tracing::{error, info, warn};
+
+/// Extension of the template files, the name of a template being the name of its file without it.
+const TEMPLATE_EXTENSION: &str = "jinja";
+
+/// Templates shipped with the extension, replaced by the files of the same name.
+const BUILT_IN_TEMPLATES: [(&str, &str); 2] = [
+    ("toddler", include_str!("../prompt_templates/toddler.jinja")),
+    (
+        "toddler.explain",
+        include_str!("../prompt_templates/toddler.explain.jinja"),
+    ),
+];
+
+/// System prompts of the agents, as Jinja templates rendered with [`PromptVariables`].
+///
+/// Templates are named after an agent, `<agent>`, or after one of its slash commands, `<agent>.<command>`,
+/// and loaded from the `<name>.jinja` files of `PROMPT_TEMPLATES_DIR` on top of the built-in ones.
+#[derive(Clone)]
+pub struct PromptTemplates {
+    directory: Option<PathBuf>,
+    environment: Arc<RwLock<Environment<'static>>>,
+}
+
+/// Variables of the templates, taken from the newest references of the request.
+#[derive(serde::Serialize, Debug, Default, Eq, PartialEq)]
+pub struct PromptVariables<'a> {
+    pub agent: &'a str,
+    /// Slash command being answered, without the leading `/`
+    pub command: Option<&'a str>,
+    /// Arguments of the slash command
+    pub args: Option<&'a str>,
+    pub user_login: &'a str,
+    pub repository: Option<RepositoryVariables<'a>>,
+    pub file: Option<FileVariables<'a>>,
+    pub selection: Option<SelectionVariables<'a>>,
+    /// URL of the page the user is chatting from, only sent by github.com
+    pub current_url: Option<&'a str>,
+}
+
+#[derive(serde::Serialize, Debug, Eq, PartialEq)]
+pub struct RepositoryVariables<'a> {
+    pub owner: &'a str,
+    pub name: &'a str,
+    /// Branch or tag, not sent by the IDEs
+    #[serde(rename = "ref")]
+    pub git_ref: Option<&'a str>,
+}
+
+#[derive(serde::Serialize, Debug, Eq, PartialEq)]
+pub struct FileVariables<'a> {
+    /// Path or URL of the file, as sent by the client
+    pub id: &'a str,
+    /// Last segment of the id
+    pub name: &'a str,
+    pub language: &'a str,
+}
+
+#[derive(serde::Serialize, Debug, Eq, PartialEq)]
+pub struct SelectionVariables<'a> {
+    pub text: &'a str,
+    /// Name of the file the selection is made in
+    pub file: &'a str,
+    pub start_line: u32,
+    pub end_line: u32,
+}
+
+impl Default for PromptTemplates {
+    fn default() -> Self {
+        Self {
+            directory: None,
+            environment: Arc::new(RwLock::new(built_in_environment())),
+        }
+    }
+}
+
+impl PromptTemplates {
+    /// Templates of `PROMPT_TEMPLATES_DIR`, on top of the built-in ones.
+    pub fn from_config(config: &Config) -> anyhow::Result<Self> {
+        config.prompt_templates_dir.as_deref().map_or_else(
+            || Ok(Self::default()),
+            |path| Self::directory(Path::new(path)),
+        )
+    }
+
+    /// Templates of a directory, on top of the built-in ones.
+    pub fn directory(path: &Path) -> anyhow::Result<Self> {
+        let environment = load(path)?;
+        info!(
+            path = %path.display(),
+            templates = ?template_names(&environment),
+            "[templates] Loaded prompt templates"
+        );
+        Ok(Self {
+            directory: Some(path.to_path_buf()),
+            environment: Arc::new(RwLock::new(environment)),
+        })
+    }
+
+    /// Loads the templates of the directory again, on the blocking thread pool, returning their names,
+    /// the current ones being kept if any template cannot be read or parsed.
+    pub async fn reload(&self) -> anyhow::Result<Vec<String>> {
+        let directory = self.directory.clone();
+        let environment = tokio::task::spawn_blocking(move || {
+            directory.map_or_else(|| Ok(built_in_environment()), |directory| load(&directory))
+        })
+        .await??;
+        let names = template_names(&environment);
+        *self
+            .environment
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = environment;
+        info!(templates = ?names, "[templates] Reloaded prompt templates");
+        Ok(names)
+    }
+
+    /// Renders the template of the command, or of the agent when the command has none, `None` when neither has one.
+    pub fn render(&self, variables: &PromptVariables) -> anyhow::Result<Option<String>> {
+        let environment = self
+            .environment
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        let names = variables
+            .command
+            .map(|command| format!("{}.{command}", variables.agent))
+            .into_iter()
+            .chain([variables.agent.to_string()]);
+        for name in names {
+            match environment.get_template(&name) {
+                Ok(template) => {
+                    let prompt = template
+                        .render(variables)
+                        .with_context(|| format!("[templates] Unable to render {name}"))?;
+                    return Ok(Some(prompt.trim().to_string()));
+                }
+                Err(err) if err.kind() == ErrorKind::TemplateNotFound => {}
+                Err(err) => {
+                    return Err(err).with_context(|| format!("[templates] Unable to load {name}"))
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Renders like [`Self::render`], falling back to the built-in templates, then to no system prompt,
+    /// so that a broken template does not fail the chat.
+    #[must_use]
+    pub fn render_or_fallback(&self, variables: &PromptVariables) -> Option<String> {
+        self.render(variables)
+            .or_else(|err| {
+                error!(error = ?err, "[templates] Unable to render, falling back to the built-in template");
+                Self::default().render(variables)
+            })
+            .unwrap_or_else(|err| {
+                error!(error = ?err, "[templates] Unable to render the built-in template, sending no system prompt");
+                None
+            })
+    }
+}
+
+fn built_in_environment() -> Environment<'static> {
+    let mut environment = Environment::new();
+    environment.set_trim_blocks(true);
+    environment.set_lstrip_blocks(true);
+    for (name, source) in BUILT_IN_TEMPLATES {
+        // built-in templates are parsed by the tests
+        let _ = environment.add_template(name, source);
+    }
+    environment
+}
+
+fn load(directory: &Path) -> anyhow::Result<Environment<'static>> {
+    let mut environment = built_in_environment();
+    let entries = std::fs::read_dir(directory)
+        .with_context(|| format!("[templates] Unable to read {}", directory.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().and_then(|extension| extension.to_str()) != Some(TEMPLATE_EXTENSION) {
+            continue;
+        }
+        let Some(name) = path.file_stem().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        let source = std::fs::read_to_string(&path)
+            .with_context(|| format!("[templates] Unable to read {}", path.display()))?;
+        environment
+            .add_template_owned(name.to_string(), source)
+            .with_context(|| format!("[templates] Unable to parse {}", path.display()))?;
+    }
+    Ok(environment)
+}
+
+fn template_names(environment: &Environment) -> Vec<String> {
+    let mut names: Vec<String> = environment
+        .templates()
+        .map(|(name, _)| name.to_string())
+        .collect();
+    names.sort();
+    names
+}
+
+impl<'a> PromptVariables<'a> {
+    #[must_use]
+    pub fn new(agent: &'a str, user_login: &'a str, request: &'a ChatRequest) -> Self {
+        // newest messages first, references keeping their order, the file of the current editor coming first
+        let references = || {
+            request
+                .messages
+                .iter()
+                .rev()
+                .flat_map(|message| &message.copilot_references)
+        };
+        let repository = references().find_map(|reference| match reference {
+            CopilotReference::GithubRepository(repository) => Some(RepositoryVariables {
+                owner: &repository.data.owner_login,
+                name: &repository.data.name,
+                git_ref: repository.data.git_ref(),
+            }),
+            _ => None,
+        });
+        let selection = references().find_map(|reference| match reference {
+            CopilotReference::ClientSelection(selection) => Some(SelectionVariables {
+                text: &selection.data.content,
+                file: &selection.id,
+                start_line: selection.data.start.line,
+                end_line: selection.data.end.line,
+            }),
+            _ => None,
+        });
+        let mut files = references().filter_map(|reference| match reference {
+            CopilotReference::ClientFile(file) => Some(FileVariables {
+                id: &file.id,
+                name: file.id.rsplit('/').next().unwrap_or_default(),
+                language: &file.data.language,
+            }),
+            _ => None,
+        });
+        let file = match &selection {
+            Some(selection) => files
+                .clone()
+                .find(|file| is_selection_file(file.id, selection.file))
+                .or_else(|| files.next()),
+            None => files.next(),
+        };
+        Self {
+            agent,
+            command: None,
+            args: None,
+            user_login,
+            repository,
+            file,
+            selection,
+            current_url: request.current_url(),
+        }
+    }
+
+    /// Selects the template of a slash command, falling back to the one of the agent.
+    #[must_use]
+    pub const fn with_command(mut self, command: &'a str, args: &'a str) -> Self {
+        self.command = Some(command);
+        self.args = Some(args);
+        self
+    }
+}
+
+/// Reloads the templates from `PROMPT_TEMPLATES_DIR`, behind the same bearer token as the metrics and hidden without one.
+pub async fn reload_prompt_templates(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(status) = debug_authorization(&state, &headers) {
+        warn!("[http] reload_prompt_templates: Missing or invalid bearer token");
+        return status.into_response();
+    }
+    match state.prompt_templates.reload().await {
+        Ok(templates) => Json(serde_json::json!({ "templates": templates })).into_response(),
+        Err(err) => {
+            error!(error = ?err, "[http] reload_prompt_templates: Keeping the current templates");
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Invalid prompt templates, the current ones are kept, see the logs for details",
+            )
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::messages::ChatRequest;
+    use crate::prompt_templates::{
+        FileVariables, PromptTemplates, PromptVariables, RepositoryVariables, SelectionVariables,
+        BUILT_IN_TEMPLATES,
+    };
+    use minijinja::Environment;
+    use std::fs;
+
+    fn sample(name: &str) -> anyhow::Result<ChatRequest> {
+        ChatRequest::parse(&fs::read_to_string(format!("samples/{name}.json"))?)
+    }
+
+    #[test]
+    fn built_in_templates_parse() -> anyhow::Result<()> {
+        for (name, source) in BUILT_IN_TEMPLATES {
+            Environment::new().add_template(name, source)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn variables_are_taken_from_the_references() -> anyhow::Result<()> {
+        let request = sample("chat_request_from_web_with_repository")?;
+        let variables = PromptVariables::new("toddler", "ledoyen", &request);
+        assert_eq!(
+            variables.repository,
+            Some(RepositoryVariables {
+                owner: "ledoyen",
+                name: "toddler-copilot-extension",
+                git_ref: Some("main"),
+            })
+        );
+        assert_eq!(
+            variables.current_url,
+            Some("https://github.com/ledoyen/toddler-copilot-extension/actions")
+        );
+        assert_eq!(variables.file, None);
+
+        let request = sample("chat_request_from_ij")?;
+        let variables = PromptVariables::new("toddler", "ledoyen", &request);
+        assert_eq!(
+            variables.file,
+            Some(FileVariables {
+                id: "file:///c%3A/workspace/toddler-copilot-extension/src/messages.rs",
+                name: "messages.rs",
+                language: "rust",
+            })
+        );
+        assert_eq!(
+            variables
+                .repository
+                .and_then(|repository| repository.git_ref),
+            None
+        );
+
+        let request = sample("chat_request_from_vs_code_with_selection")?;
+        let selection = PromptVariables::new("toddler", "ledoyen", &request).selection;
+        assert!(matches!(
+            selection,
+            Some(SelectionVariables {
+                file: "+page.svelte",
+                start_line: 44,
+                end_line: 51,
+                ..
+            })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn built_in_template_describes_the_context() -> anyhow::Result<()> {
+        let request = sample("chat_request_from_web_with_repository")?;
+        let prompt = PromptTemplates::default()
+            .render(&PromptVariables::new("toddler", "ledoyen", &request))?;
+        assert_eq!(
+            prompt.as_deref(),
+            Some(
+                "You are Toddler, a GitHub Copilot extension helping ledoyen with their code.\n\
+                 Answer in Markdown, concisely, and say so when you do not know.\n\
+                 The user works on the repository `ledoyen/toddler-copilot-extension`, on `main`.\n\
+                 The user is chatting from https://github.com/ledoyen/toddler-copilot-extension/actions."
+            )
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn templates_are_selected_by_command_then_by_agent() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let directory = dir.path();
+        fs::write(directory.join("toddler.jinja"), "Hello {{ user_login }}")?;
+        fs::write(
+            directory.join("toddler.review.jinja"),
+            "Review {{ args }} for {{ user_login }}",
+        )?;
+        fs::write(directory.join("notes.txt"), "{{ not a template")?;
+        let request = sample("chat_request_from_vs_code")?;
+        let templates = PromptTemplates::directory(directory)?;
+        let render = |variables: PromptVariables| templates.render(&variables);
+
+        let variables = || PromptVariables::new("toddler", "ledoyen", &request);
+        assert_eq!(render(variables())?.as_deref(), Some("Hello ledoyen"));
+        assert_eq!(
+            render(variables().with_command("review", "main.rs"))?.as_deref(),
+            Some("Review main.rs for ledoyen")
+        );
+        assert_eq!(
+            render(variables().with_command("logout", ""))?.as_deref(),
+            Some("Hello ledoyen")
+        );
+        // built-in templates not overridden are kept
+        assert!(render(variables().with_command("explain", ""))?
+            .is_some_and(|prompt| prompt.contains("Explain the selected code")));
+        assert_eq!(
+            render(PromptVariables::new("other", "ledoyen", &request))?,
+            None
+        );
+
+        // templates failing to parse are reported, the current ones being kept
+        fs::write(directory.join("toddler.jinja"), "Hello {{ user_login")?;
+        let reloaded = templates.reload().await;
+        assert!(reloaded.is_err());
+        assert_eq!(render(variables())?.as_deref(), Some("Hello ledoyen"));
+
+        fs::write(directory.join("toddler.jinja"), "Hi {{ user_login }}")?;
+        let reloaded = templates.reload().await?;
+        assert_eq!(
+            reloaded,
+            vec!["toddler", "toddler.explain", "toddler.review"]
+        );
+        assert_eq!(render(variables())?.as_deref(), Some("Hi ledoyen"));
+        Ok(())
+    }
+
+    #[test]
+    fn templates_failing_to_render_fall_back_to_the_built_in_ones() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(
+            dir.path().join("toddler.jinja"),
+            "Hello {{ user_login | no_such_filter }}",
+        )?;
+        let request = sample("chat_request_from_web_with_repository")?;
+        let variables = PromptVariables::new("toddler", "ledoyen", &request);
+        let templates = PromptTemplates::directory(dir.path())?;
+        assert!(templates.render(&variables).is_err());
+        assert_eq!(
+            templates.render_or_fallback(&variables),
+            PromptTemplates::default().render(&variables)?
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,180 @@
+use axum::http::HeaderMap;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+pub(crate) const REDACTED: &str = "[REDACTED]";
+
+/// Headers carrying credentials, never written as is.
+const SENSITIVE_HEADERS: [&str; 5] = [
+    "authorization",
+    "cookie",
+    "proxy-authorization",
+    "set-cookie",
+    "x-github-token",
+];
+
+/// Payload fields redacted by default: contents of files and selections sent as references.
+const DEFAULT_REDACTED_PATHS: [&str; 1] = ["messages.*.copilot_references.*.data.content"];
+
+/// Returns a printable copy of the headers, with the values of sensitive ones redacted.
+#[must_use]
+pub fn redact_headers(headers: &HeaderMap) -> BTreeMap<String, String> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if SENSITIVE_HEADERS.contains(&name.as_str()) {
+                REDACTED.to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
+/// Redacts fields of JSON payloads designated by dot-separated paths,
+/// where `*` matches any object key or array element, e.g. `messages.*.content`.
+#[derive(Clone, Debug)]
+pub struct Redactor {
+    paths: Vec<Vec<String>>,
+}
+
+impl Default for Redactor {
+    fn default() -> Self {
+        Self::new(DEFAULT_REDACTED_PATHS)
+    }
+}
+
+impl Redactor {
+    pub fn new<I, T>(paths: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<str>,
+    {
+        Self {
+            paths: paths
+                .into_iter()
+                .map(|path| path.as_ref().split('.').map(String::from).collect())
+                .collect(),
+        }
+    }
+
+    /// Default paths, extended with a comma-separated list of additional ones.
+    #[must_use]
+    pub fn with_additional_paths(additional_paths: Option<&str>) -> Self {
+        let additional_paths = additional_paths
+            .into_iter()
+            .flat_map(|paths| paths.split(','))
+            .map(str::trim)
+            .filter(|path| !path.is_empty());
+        Self::new(DEFAULT_REDACTED_PATHS.into_iter().chain(additional_paths))
+    }
+
+    pub fn redact(&self, value: &mut Value) {
+        for path in &self.paths {
+            redact_path(value, path);
+        }
+    }
+
+    /// Parses the body as JSON and redacts it, falling back to a placeholder when it is not JSON.
+    #[must_use]
+    pub fn redact_body(&self, body: &str) -> Value {
+        serde_json::from_str(body).map_or_else(
+            |_| Value::String(format!("[UNPARSEABLE BODY, {} bytes]", body.len())),
+            |mut value| {
+                self.redact(&mut value);
+                value
+            },
+        )
+    }
+}
+
+fn redact_path(value: &mut Value, path: &[String]) {
+    let Some((segment, rest)) = path.split_first() else {
+        if !value.is_null() {
+            *value = Value::String(REDACTED.to_string());
+        }
+        return;
+    };
+    match value {
+        Value::Object(map) if segment == "*" => {
+            for child in map.values_mut() {
+                redact_path(child, rest);
+            }
+        }
+        Value::Object(map) => {
+            if let Some(child) = map.get_mut(segment) {
+                redact_path(child, rest);
+            }
+        }
+        Value::Array(items) if segment == "*" => {
+            for child in items {
+                redact_path(child, rest);
+            }
+        }
+        Value::Array(items) => {
+            if let Some(child) = segment.parse::<usize>().ok().and_then(|i| items.get_mut(i)) {
+                redact_path(child, rest);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::redaction::{redact_headers, Redactor};
+    use axum::http::{HeaderMap, HeaderValue};
+    use serde_json::json;
+    use std::fs;
+
+    #[test]
+    fn sensitive_headers_are_redacted() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-github-token", HeaderValue::from_static("ghu_secret"));
+        headers.insert(
+            "copilot-integration-id",
+            HeaderValue::from_static("vscode-chat"),
+        );
+        let redacted = redact_headers(&headers);
+        assert_eq!(redacted["x-github-token"], "[REDACTED]");
+        assert_eq!(redacted["copilot-integration-id"], "vscode-chat");
+    }
+
+    #[test]
+    fn default_paths_redact_file_contents() -> anyhow::Result<()> {
+        let payload = fs::read_to_string(
+            "samples/chat_request_from_vs_code_withcurrent_editor_and_file_context.json",
+        )?;
+        let redacted = Redactor::default().redact_body(&payload);
+        let references = &redacted["messages"][4]["copilot_references"];
+        assert_eq!(references[0]["data"]["content"], "[REDACTED]");
+        assert_eq!(references[0]["data"]["language"], "svelte");
+        assert_eq!(redacted["messages"][4]["content"], "do something");
+        Ok(())
+    }
+
+    #[test]
+    fn additional_paths_support_wildcards_and_indexes() {
+        let redactor =
+            Redactor::with_additional_paths(Some("messages.*.content, agent,messages.0.role"));
+        let mut value = json!({
+            "agent": "toddler",
+            "messages": [
+                {"role": "user", "content": "hello"},
+                {"role": "assistant", "content": null},
+            ],
+        });
+        redactor.redact(&mut value);
+        assert_eq!(
+            value,
+            json!({
+                "agent": "[REDACTED]",
+                "messages": [
+                    {"role": "[REDACTED]", "content": "[REDACTED]"},
+                    {"role": "assistant", "content": null},
+                ],
+            })
+        );
+    }
+}
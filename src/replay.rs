@@ -0,0 +1,296 @@
+use crate::capture::CapturedRequest;
+use crate::copilot_public_keys::{CopilotPublicKey, CopilotPublicKeys};
+use crate::llm::DeltaParser;
+use crate::redaction::REDACTED;
+use anyhow::Context;
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use p256::ecdsa::{DerSignature, SigningKey};
+use p256::pkcs8::{DecodePrivateKey, EncodePrivateKey, EncodePublicKey, LineEnding};
+use rand_core::OsRng;
+use signature::Signer;
+use similar::TextDiff;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Captured headers not sent again, as they are set by the HTTP client or replaced by the replay.
+const SKIPPED_HEADERS: [&str; 7] = [
+    "host",
+    "content-length",
+    "connection",
+    "transfer-encoding",
+    "accept-encoding",
+    "github-public-key-signature",
+    "github-public-key-identifier",
+];
+
+/// Signs replayed payloads with a local key, in place of Copilot.
+pub struct ReplaySigner {
+    key: SigningKey,
+    key_identifier: String,
+}
+
+impl ReplaySigner {
+    #[must_use]
+    pub fn generate(key_identifier: &str) -> Self {
+        Self {
+            key: SigningKey::random(&mut OsRng),
+            key_identifier: key_identifier.to_string(),
+        }
+    }
+
+    /// Reads a PKCS#8 PEM private key, as written by [`ReplaySigner::write`].
+    pub fn from_pem_file(path: &Path, key_identifier: &str) -> anyhow::Result<Self> {
+        let pem = std::fs::read_to_string(path)
+            .with_context(|| format!("[replay] Unable to read {}", path.display()))?;
+        let key = SigningKey::from_pkcs8_pem(&pem)
+            .with_context(|| format!("[replay] Invalid private key in {}", path.display()))?;
+        Ok(Self {
+            key,
+            key_identifier: key_identifier.to_string(),
+        })
+    }
+
+    /// Writes the private key, and the public one in the format of the published Copilot keys,
+    /// to be trusted by the server through `TRUSTED_TEST_KEYS_FILE`.
+    pub fn write(&self, private_key_path: &Path, public_keys_path: &Path) -> anyhow::Result<()> {
+        std::fs::write(
+            private_key_path,
+            self.key.to_pkcs8_pem(LineEnding::LF)?.as_bytes(),
+        )
+        .with_context(|| format!("[replay] Unable to write {}", private_key_path.display()))?;
+        std::fs::write(
+            public_keys_path,
+            serde_json::to_string_pretty(&self.public_keys()?)?,
+        )
+        .with_context(|| format!("[replay] Unable to write {}", public_keys_path.display()))?;
+        Ok(())
+    }
+
+    /// Public key, in the format of the published Copilot keys.
+    pub fn public_keys(&self) -> anyhow::Result<CopilotPublicKeys> {
+        Ok(CopilotPublicKeys {
+            public_keys: vec![CopilotPublicKey {
+                key_identifier: self.key_identifier.clone(),
+                key: self.key.verifying_key().to_public_key_pem(LineEnding::LF)?,
+                is_current: false,
+            }],
+        })
+    }
+
+    /// Signs the body as Copilot does: a base64 encoded DER ECDSA P-256 signature.
+    #[must_use]
+    pub fn sign(&self, body: &str) -> String {
+        let signature: DerSignature = self.key.sign(body.as_bytes());
+        BASE64_STANDARD.encode(signature.as_bytes())
+    }
+}
+
+/// A request to replay, read from a capture file or a sample payload.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ReplayPayload {
+    /// Name of the file, followed by the line number for capture files
+    pub name: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl ReplayPayload {
+    /// Reads the requests of a capture file (`.jsonl`), or the body of a sample payload (any other extension).
+    pub fn read_all(path: &Path) -> anyhow::Result<Vec<Self>> {
+        let stem = path.file_stem().map_or_else(
+            || path.display().to_string(),
+            |stem| stem.to_string_lossy().into_owned(),
+        );
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "jsonl")
+        {
+            Ok(CapturedRequest::read_all(path)?
+                .into_iter()
+                .enumerate()
+                .map(|(index, request)| Self {
+                    name: format!("{stem}-{}", index + 1),
+                    headers: request
+                        .headers
+                        .into_iter()
+                        .filter(|(name, value)| {
+                            value != REDACTED && !SKIPPED_HEADERS.contains(&name.as_str())
+                        })
+                        .collect(),
+                    body: request.body.to_string(),
+                })
+                .collect())
+        } else {
+            let body = std::fs::read_to_string(path)
+                .with_context(|| format!("[replay] Unable to read {}", path.display()))?;
+            Ok(vec![Self {
+                name: stem,
+                headers: vec![("copilot-integration-id".to_string(), "replay".to_string())],
+                body,
+            }])
+        }
+    }
+}
+
+/// Response of the agent to a replayed request.
+#[derive(Debug)]
+pub struct ReplayOutcome {
+    pub status: u16,
+    pub latency: Duration,
+    /// Content of the streamed answer, concatenated
+    pub output: String,
+}
+
+/// Posts payloads to the `/agent` endpoint of a running server, signed as if they came from Copilot.
+pub struct Replayer {
+    http: reqwest::Client,
+    agent_url: String,
+    signer: ReplaySigner,
+    github_token: String,
+}
+
+impl Replayer {
+    #[must_use]
+    pub fn new(base_url: &str, signer: ReplaySigner, github_token: String) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            agent_url: format!("{base_url}/agent"),
+            signer,
+            github_token,
+        }
+    }
+
+    pub async fn replay(&self, payload: &ReplayPayload) -> anyhow::Result<ReplayOutcome> {
+        let mut request = self.http.post(&self.agent_url);
+        for (name, value) in &payload.headers {
+            request = request.header(name, value);
+        }
+        let started_at = Instant::now();
+        let response = request
+            .header("content-type", "application/json")
+            .header("x-github-token", &self.github_token)
+            .header(
+                "github-public-key-signature",
+                self.signer.sign(&payload.body),
+            )
+            .header("github-public-key-identifier", &self.signer.key_identifier)
+            .body(payload.body.clone())
+            .send()
+            .await
+            .with_context(|| format!("[replay] Unable to reach {}", self.agent_url))?;
+        let status = response.status().as_u16();
+        let body = response.bytes().await?;
+        let latency = started_at.elapsed();
+        let output = DeltaParser::default().push(&body).concat();
+        Ok(ReplayOutcome {
+            status,
+            latency,
+            output,
+        })
+    }
+}
+
+/// Unified diff between the expected and actual outputs, `None` when they are the same.
+#[must_use]
+pub fn diff(expected: &str, actual: &str) -> Option<String> {
+    (expected != actual).then(|| {
+        TextDiff::from_lines(expected, actual)
+            .unified_diff()
+            .header("expected", "actual")
+            .to_string()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::copilot_public_keys::CopilotKeyStore;
+    use crate::events;
+    use crate::metrics::Metrics;
+    use crate::replay::{diff, ReplayPayload, ReplaySigner, Replayer};
+    use axum::extract::State;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::response::{IntoResponse, Response};
+    use axum::routing::{get, post};
+    use axum::{Json, Router};
+    use std::path::Path;
+
+    async fn mock_agent(
+        State(keys): State<CopilotKeyStore>,
+        headers: HeaderMap,
+        body: String,
+    ) -> Response {
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+        let verified = keys
+            .verify(
+                header("github-public-key-identifier"),
+                header("github-public-key-signature").unwrap_or_default(),
+                &body,
+            )
+            .await;
+        match (verified, header("copilot-integration-id")) {
+            (Ok(()), Some(integration_id)) => {
+                events::message_response(&format!("{integration_id} sent {} bytes", body.len()))
+            }
+            _ => StatusCode::BAD_REQUEST.into_response(),
+        }
+    }
+
+    #[tokio::test]
+    async fn samples_are_signed_and_answers_collected() -> anyhow::Result<()> {
+        let signer = ReplaySigner::generate("replay");
+        let dir = std::env::temp_dir().join("toddler_replay_keys");
+        std::fs::create_dir_all(&dir)?;
+        signer.write(&dir.join("replay_key.pem"), &dir.join("replay_keys.json"))?;
+        let signer = ReplaySigner::from_pem_file(&dir.join("replay_key.pem"), "replay")?;
+
+        // the server publishes an unrelated key and trusts the replay one from a file
+        let published = ReplaySigner::generate("published").public_keys()?;
+        let mut published = serde_json::to_value(published)?;
+        published["public_keys"][0]["is_current"] = true.into();
+        let keys_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let keys_url = format!("http://{}/keys", keys_listener.local_addr()?);
+        let keys_router = Router::new().route(
+            "/keys",
+            get(move || {
+                let published = published.clone();
+                async move { Json(published) }
+            }),
+        );
+        tokio::spawn(async move { axum::serve(keys_listener, keys_router).await });
+        let keys = CopilotKeyStore::load(&keys_url, Metrics::default())
+            .await?
+            .with_trusted_keys_file(&dir.join("replay_keys.json"))?;
+        std::fs::remove_dir_all(&dir)?;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let base_url = format!("http://{}", listener.local_addr()?);
+        let router = Router::new()
+            .route("/agent", post(mock_agent))
+            .with_state(keys);
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let payloads = ReplayPayload::read_all(Path::new("samples/chat_request_from_ij.json"))?;
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(payloads[0].name, "chat_request_from_ij");
+        let replayer = Replayer::new(&base_url, signer, "ghu_test".to_string());
+        let outcome = replayer.replay(&payloads[0]).await?;
+        assert_eq!(outcome.status, 200);
+        assert_eq!(
+            outcome.output,
+            format!("replay sent {} bytes", payloads[0].body.len())
+        );
+
+        let forged = Replayer::new(&base_url, ReplaySigner::generate("replay"), String::new());
+        assert_eq!(forged.replay(&payloads[0]).await?.status, 400);
+        Ok(())
+    }
+
+    #[test]
+    fn diff_shows_changed_lines() {
+        assert_eq!(diff("Hello\nworld\n", "Hello\nworld\n"), None);
+        let diff = diff("Hello\nworld\n", "Hello\nthere\n").unwrap_or_default();
+        assert!(diff.contains("-world"));
+        assert!(diff.contains("+there"));
+    }
+}
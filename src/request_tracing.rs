@@ -0,0 +1,95 @@
+use axum::extract::MatchedPath;
+use axum::http::{HeaderName, Request, Response};
+use axum::Router;
+use std::time::Duration;
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
+use tower_http::trace::TraceLayer;
+use tracing::field::Empty;
+use tracing::{info, info_span, Span};
+
+/// Header carrying the correlation id, taken from the request when present, generated otherwise.
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Wraps every request in a span carrying its method, route, request id, then its status and latency.
+/// The request id is sent back in the response.
+pub fn with_request_tracing(router: Router) -> Router {
+    router
+        .layer(PropagateRequestIdLayer::new(REQUEST_ID_HEADER))
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(make_span)
+                .on_request(())
+                .on_response(on_response)
+                .on_failure(()),
+        )
+        .layer(SetRequestIdLayer::new(REQUEST_ID_HEADER, MakeRequestUuid))
+}
+
+fn make_span<B>(request: &Request<B>) -> Span {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| request.uri().path(), MatchedPath::as_str);
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    info_span!(
+        "http",
+        method = %request.method(),
+        route,
+        request_id,
+        status = Empty,
+        latency_ms = Empty,
+    )
+}
+
+fn on_response<B>(response: &Response<B>, latency: Duration, span: &Span) {
+    span.record("status", response.status().as_u16());
+    span.record("latency_ms", latency.as_millis());
+    info!("[http] Response sent");
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::request_tracing::{with_request_tracing, REQUEST_ID_HEADER};
+    use axum::body::Body;
+    use axum::http::Request;
+    use axum::routing::get;
+    use axum::Router;
+    use tower::ServiceExt;
+
+    fn router() -> Router {
+        with_request_tracing(Router::new().route("/ping", get(|| async { "pong" })))
+    }
+
+    #[tokio::test]
+    async fn request_id_is_generated() -> anyhow::Result<()> {
+        let response = router()
+            .oneshot(Request::get("/ping").body(Body::empty())?)
+            .await?;
+        let request_id = response.headers().get(REQUEST_ID_HEADER);
+        assert!(request_id.is_some_and(|id| id.len() == 36));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn request_id_is_propagated() -> anyhow::Result<()> {
+        let response = router()
+            .oneshot(
+                Request::get("/ping")
+                    .header(REQUEST_ID_HEADER, "from-the-caller")
+                    .body(Body::empty())?,
+            )
+            .await?;
+        assert_eq!(
+            response
+                .headers()
+                .get(REQUEST_ID_HEADER)
+                .map(|id| id.as_bytes()),
+            Some(b"from-the-caller".as_slice())
+        );
+        Ok(())
+    }
+}
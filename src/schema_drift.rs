@@ -0,0 +1,264 @@
+use crate::messages::{ChatRequest, CopilotReference};
+use crate::metrics::{debug_authorization, Metrics};
+use crate::state::AppState;
+use axum::extract::State;
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use tracing::{debug, warn};
+
+/// Maximum number of distinct observations of each kind, so that odd payloads cannot grow memory or metric labels without bound.
+const MAX_OBSERVATIONS: usize = 100;
+
+/// Tracks the differences between the requests sent by Copilot and the shapes the extension knows,
+/// to notice changes of the undocumented payload format.
+#[derive(Clone, Default)]
+pub struct SchemaDrift {
+    report: Arc<RwLock<DriftReport>>,
+}
+
+/// What was observed since the start, keyed by reference type or field path.
+#[derive(serde::Serialize, Clone, Debug, Default)]
+pub struct DriftReport {
+    pub unknown_reference_types: BTreeMap<String, Observation>,
+    /// Keyed by `<reference type>: <path of the offending field>`
+    pub malformed_references: BTreeMap<String, Observation>,
+    /// Keyed by path, `*` standing for array elements and `[type]` for references of that type
+    pub unknown_fields: BTreeMap<String, Observation>,
+}
+
+#[derive(serde::Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct Observation {
+    pub count: u64,
+    /// Shape of the first observed value, with the type of each value in place of its content
+    pub example_shape: Value,
+}
+
+impl SchemaDrift {
+    /// Records the unknown references and fields of a request, comparing its body to what was parsed from it.
+    ///
+    /// The comparison happens before locking the report, which is only written to when something drifted.
+    pub fn observe(&self, body: &str, request: &ChatRequest, metrics: &Metrics) {
+        let (Ok(raw), Ok(parsed)) = (
+            serde_json::from_str::<Value>(body),
+            serde_json::to_value(request),
+        ) else {
+            return;
+        };
+        let unknown_references: Vec<_> = request
+            .messages
+            .iter()
+            .flat_map(|message| &message.copilot_references)
+            .filter_map(|reference| match reference {
+                CopilotReference::Unknown(unknown) => Some(unknown),
+                _ => None,
+            })
+            .collect();
+        let mut unknown_fields = vec![];
+        collect_unknown_fields(&raw, &parsed, "", &mut unknown_fields);
+        if unknown_references.is_empty() && unknown_fields.is_empty() {
+            return;
+        }
+
+        let mut report = self
+            .report
+            .write()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        for unknown in unknown_references {
+            let reference_type = unknown.reference_type.as_deref().unwrap_or("<none>");
+            match &unknown.error {
+                None => {
+                    if record(
+                        &mut report.unknown_reference_types,
+                        reference_type,
+                        &unknown.value,
+                    ) {
+                        metrics.unknown_reference_type(reference_type);
+                    }
+                }
+                Some(error) => {
+                    let key = format!("{reference_type}: {}", error.path);
+                    if record(&mut report.malformed_references, &key, &unknown.value) {
+                        metrics.malformed_reference(reference_type);
+                    }
+                }
+            }
+        }
+        for (path, value) in unknown_fields {
+            debug!(path, "[schema] Unknown field in agent request");
+            if record(&mut report.unknown_fields, &path, value) {
+                metrics.unknown_field(&path);
+            }
+        }
+    }
+
+    #[must_use]
+    pub fn report(&self) -> DriftReport {
+        self.report
+            .read()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .clone()
+    }
+}
+
+/// Counts an observation, returning `false` when it is dropped because too many distinct ones were recorded.
+fn record(observations: &mut BTreeMap<String, Observation>, key: &str, value: &Value) -> bool {
+    if let Some(observation) = observations.get_mut(key) {
+        observation.count += 1;
+        return true;
+    }
+    if observations.len() >= MAX_OBSERVATIONS {
+        return false;
+    }
+    observations.insert(
+        key.to_string(),
+        Observation {
+            count: 1,
+            example_shape: shape(value),
+        },
+    );
+    true
+}
+
+/// Replaces every value by the name of its type, keeping the structure of objects and the first element of arrays.
+#[must_use]
+pub fn shape(value: &Value) -> Value {
+    match value {
+        Value::Null => "null".into(),
+        Value::Bool(_) => "boolean".into(),
+        Value::Number(_) => "number".into(),
+        Value::String(_) => "string".into(),
+        Value::Array(values) => Value::Array(values.first().map(shape).into_iter().collect()),
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(name, value)| (name.clone(), shape(value)))
+                .collect(),
+        ),
+    }
+}
+
+/// Collects the fields of the raw payload that are lost once parsed, with their paths.
+fn collect_unknown_fields<'a>(
+    raw: &'a Value,
+    parsed: &Value,
+    path: &str,
+    unknown_fields: &mut Vec<(String, &'a Value)>,
+) {
+    match (raw, parsed) {
+        (Value::Object(raw_fields), Value::Object(parsed_fields)) => {
+            for (name, raw_value) in raw_fields {
+                let field_path = if path.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{path}.{name}")
+                };
+                match parsed_fields.get(name) {
+                    Some(parsed_value) => {
+                        collect_unknown_fields(
+                            raw_value,
+                            parsed_value,
+                            &field_path,
+                            unknown_fields,
+                        );
+                    }
+                    None => unknown_fields.push((field_path, raw_value)),
+                }
+            }
+        }
+        (Value::Array(raw_values), Value::Array(parsed_values)) => {
+            for (raw_value, parsed_value) in raw_values.iter().zip(parsed_values) {
+                let element = match raw_value.get("type").and_then(Value::as_str) {
+                    Some(reference_type) if path.ends_with("copilot_references") => {
+                        format!("{path}[{reference_type}]")
+                    }
+                    _ => format!("{path}.*"),
+                };
+                collect_unknown_fields(raw_value, parsed_value, &element, unknown_fields);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Shows what was observed, behind the same bearer token as the metrics and hidden without one.
+#[allow(clippy::unused_async)]
+pub async fn schema_drift(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Err(status) = debug_authorization(&state, &headers) {
+        warn!("[http] schema_drift: Missing or invalid bearer token");
+        return status.into_response();
+    }
+    Json(state.schema_drift.report()).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::messages::ChatRequest;
+    use crate::metrics::Metrics;
+    use crate::schema_drift::SchemaDrift;
+    use std::fs;
+
+    #[test]
+    fn unknown_references_and_fields_are_reported() -> anyhow::Result<()> {
+        let body = fs::read_to_string("samples/chat_request_from_vs_code_with_selection.json")?;
+        let mut body: serde_json::Value = serde_json::from_str(&body)?;
+        body["messages"][2]["copilot_references"][0]["type"] = "client.diagnostics".into();
+        body["messages"][2]["copilot_references"][1]["data"]["topics"] =
+            serde_json::json!(["rust"]);
+        let body = body.to_string();
+        let request = ChatRequest::parse(&body)?;
+        let drift = SchemaDrift::default();
+        let metrics = Metrics::default();
+        drift.observe(&body, &request, &metrics);
+        drift.observe(&body, &request, &metrics);
+
+        let report = drift.report();
+        let diagnostics = &report.unknown_reference_types["client.diagnostics"];
+        assert_eq!(diagnostics.count, 2);
+        assert_eq!(diagnostics.example_shape["data"]["content"], "string");
+        assert_eq!(diagnostics.example_shape["data"]["start"]["line"], "number");
+        let topics =
+            &report.unknown_fields["messages.*.copilot_references[github.repository].data.topics"];
+        assert_eq!(topics.count, 2);
+        assert_eq!(topics.example_shape, serde_json::json!(["string"]));
+        assert!(!report.unknown_fields.contains_key("agent"));
+        assert!(report.malformed_references.is_empty());
+
+        let encoded = metrics.encode()?;
+        assert!(encoded.contains(
+            r#"toddler_unknown_reference_types_total{reference_type="client.diagnostics"} 2"#
+        ));
+        assert!(encoded.contains(
+            r#"toddler_unknown_fields_total{path="messages.*.copilot_references[github.repository].data.topics"} 2"#
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_references_are_keyed_by_path() -> anyhow::Result<()> {
+        let body = serde_json::json!({
+            "copilot_thread_id": "",
+            "messages": [{ "role": "user", "content": "", "copilot_references": [
+                { "type": "github.current-url", "data": { "url": 42 }, "id": "", "is_implicit": true,
+                  "metadata": { "display_name": "", "display_icon": "", "display_url": "" } }
+            ] }],
+        })
+        .to_string();
+        let request = ChatRequest::parse(&body)?;
+        let drift = SchemaDrift::default();
+        drift.observe(&body, &request, &Metrics::default());
+
+        let report = drift.report();
+        assert_eq!(
+            report.malformed_references["github.current-url: data.url"].example_shape["data"]
+                ["url"],
+            "number"
+        );
+        // the content of unknown references is not reported again as unknown fields
+        assert!(report.unknown_fields.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,248 @@
+use crate::agent::{message, AgentContext, AgentError, AgentEvents};
+use futures::future::BoxFuture;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::future::Future;
+use std::sync::Arc;
+
+type Handler = Arc<
+    dyn Fn(AgentContext, String) -> BoxFuture<'static, Result<AgentEvents, AgentError>>
+        + Send
+        + Sync,
+>;
+
+#[derive(Clone)]
+struct Command {
+    description: String,
+    handler: Handler,
+}
+
+/// Commands an agent answers when a message starts with `/command`, optionally after the `@agent` mention.
+///
+/// `/help`, listing the registered commands with their description, is always available.
+#[derive(Clone, Default)]
+pub struct SlashCommands {
+    commands: Arc<BTreeMap<String, Command>>,
+}
+
+/// A command sent by the user, with the arguments following its name.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SlashCommand<'a> {
+    pub name: &'a str,
+    pub args: &'a str,
+}
+
+/// Command resolved from a message, ready to be answered.
+pub struct Invocation {
+    pub name: String,
+    pub args: String,
+    action: Action,
+}
+
+enum Action {
+    Run(Handler),
+    Answer(String),
+}
+
+impl SlashCommands {
+    /// Registers a command, `name` being given without the leading `/`.
+    #[must_use]
+    pub fn with_command<F, Fut>(mut self, name: &str, description: &str, handler: F) -> Self
+    where
+        F: Fn(AgentContext, String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<AgentEvents, AgentError>> + Send + 'static,
+    {
+        let handler: Handler = Arc::new(move |context, args| Box::pin(handler(context, args)));
+        Arc::make_mut(&mut self.commands).insert(
+            name.to_string(),
+            Command {
+                description: description.to_string(),
+                handler,
+            },
+        );
+        self
+    }
+
+    /// Command of the message, optionally mentioning `agent`, `None` when the message is not a command.
+    ///
+    /// Unknown commands are not captured, so that messages such as `/usr is full?` are answered as any other.
+    #[must_use]
+    pub fn resolve(&self, content: &str, agent: Option<&str>) -> Option<Invocation> {
+        let command = parse_command(content, agent)?;
+        let action = match self.commands.get(command.name) {
+            Some(registered) => Action::Run(registered.handler.clone()),
+            None if command.name == "help" => Action::Answer(self.help()),
+            None => return None,
+        };
+        Some(Invocation {
+            name: command.name.to_string(),
+            args: command.args.to_string(),
+            action,
+        })
+    }
+
+    /// Markdown list of the available commands, sorted by name.
+    #[must_use]
+    pub fn help(&self) -> String {
+        let mut commands: BTreeMap<&str, &str> = self
+            .commands
+            .iter()
+            .map(|(name, command)| (name.as_str(), command.description.as_str()))
+            .collect();
+        commands
+            .entry("help")
+            .or_insert("Lists the available commands, with what they do");
+        let mut help = "Available commands:\n".to_string();
+        for (name, description) in commands {
+            let _ = write!(help, "\n* `/{name}`: {description}");
+        }
+        help
+    }
+}
+
+impl Invocation {
+    pub async fn run(self, context: AgentContext) -> Result<AgentEvents, AgentError> {
+        match self.action {
+            Action::Run(handler) => handler(context, self.args).await,
+            Action::Answer(answer) => Ok(message(answer)),
+        }
+    }
+}
+
+/// Removes the mention of `agent` starting the message, if any, other mentions being part of the message.
+#[must_use]
+pub fn strip_mention<'a>(content: &'a str, agent: Option<&str>) -> &'a str {
+    let content = content.trim();
+    agent
+        .and_then(|agent| {
+            let mentioned = content.strip_prefix('@')?;
+            let rest = mentioned
+                .get(..agent.len())
+                .filter(|name| name.eq_ignore_ascii_case(agent))
+                .map(|_| &mentioned[agent.len()..])?;
+            (rest.is_empty() || rest.starts_with(char::is_whitespace)).then(|| rest.trim_start())
+        })
+        .unwrap_or(content)
+}
+
+/// Parses `/command args`, optionally preceded by the mention of `agent`.
+///
+/// Command names are made of ASCII letters, digits, `-` and `_`,
+/// so that messages starting with a path such as `/etc/hosts` are not commands.
+#[must_use]
+pub fn parse_command<'a>(content: &'a str, agent: Option<&str>) -> Option<SlashCommand<'a>> {
+    let command = strip_mention(content, agent).strip_prefix('/')?;
+    let (name, args) = command
+        .split_once(char::is_whitespace)
+        .map_or((command, ""), |(name, args)| (name, args.trim()));
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    valid.then_some(SlashCommand { name, args })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::agent::{message, AgentContext, AgentError, AgentEvents};
+    use crate::slash_commands::{parse_command, strip_mention, SlashCommand, SlashCommands};
+
+    async fn explain(_: AgentContext, args: String) -> Result<AgentEvents, AgentError> {
+        Ok(message(args))
+    }
+
+    const AGENT: Option<&str> = Some("mais-arreeeeeeeeteuuuu");
+
+    #[test]
+    fn mention_is_stripped() {
+        assert_eq!(
+            strip_mention("@mais-arreeeeeeeeteuuuu coucou", AGENT),
+            "coucou"
+        );
+        assert_eq!(
+            strip_mention("  @Mais-Arreeeeeeeeteuuuu \n /help ", AGENT),
+            "/help"
+        );
+        assert_eq!(strip_mention("@mais-arreeeeeeeeteuuuu", AGENT), "");
+        assert_eq!(
+            strip_mention("coucou @mais-arreeeeeeeeteuuuu", AGENT),
+            "coucou @mais-arreeeeeeeeteuuuu"
+        );
+    }
+
+    #[test]
+    fn other_mentions_are_kept() {
+        assert_eq!(
+            strip_mention("@Override why is it needed?", AGENT),
+            "@Override why is it needed?"
+        );
+        assert_eq!(strip_mention("@octocat /help", AGENT), "@octocat /help");
+        assert_eq!(
+            strip_mention("@mais-arreeeeeeeeteuuuu-bis coucou", AGENT),
+            "@mais-arreeeeeeeeteuuuu-bis coucou"
+        );
+        assert_eq!(
+            strip_mention("@mais-arreeeeeeeeteuuuu coucou", None),
+            "@mais-arreeeeeeeeteuuuu coucou"
+        );
+    }
+
+    #[test]
+    fn commands_are_parsed_with_their_arguments() {
+        assert_eq!(
+            parse_command("@mais-arreeeeeeeeteuuuu /explain  this  function ", AGENT),
+            Some(SlashCommand {
+                name: "explain",
+                args: "this  function"
+            })
+        );
+        assert_eq!(
+            parse_command("/logout", AGENT),
+            Some(SlashCommand {
+                name: "logout",
+                args: ""
+            })
+        );
+        assert_eq!(parse_command("how do I /logout?", AGENT), None);
+        assert_eq!(parse_command("/etc/hosts is empty", AGENT), None);
+        assert_eq!(parse_command("/", AGENT), None);
+        assert_eq!(parse_command("@mais-arreeeeeeeeteuuuu", AGENT), None);
+        assert_eq!(parse_command("@octocat /help", AGENT), None);
+    }
+
+    #[test]
+    fn registered_commands_are_resolved() {
+        let commands =
+            SlashCommands::default().with_command("explain", "Explains the selected code", explain);
+        let invocation = commands.resolve("@mais-arreeeeeeeeteuuuu /explain main", AGENT);
+        assert_eq!(
+            invocation.map(|invocation| (invocation.name, invocation.args)),
+            Some(("explain".to_string(), "main".to_string()))
+        );
+        assert!(commands.resolve("explain main", AGENT).is_none());
+    }
+
+    #[test]
+    fn unknown_commands_are_not_captured() {
+        let commands =
+            SlashCommands::default().with_command("explain", "Explains the selected code", explain);
+        assert!(commands.resolve("/usr is full?", AGENT).is_none());
+        assert!(commands.resolve("/tmp", AGENT).is_none());
+        assert!(commands.resolve("/tmp/cache is full", AGENT).is_none());
+    }
+
+    #[test]
+    fn help_lists_registered_commands() {
+        let commands = SlashCommands::default()
+            .with_command("logout", "Disconnects your GitHub account", explain)
+            .with_command("explain", "Explains the selected code", explain);
+        assert_eq!(
+            commands.help(),
+            "Available commands:\n\
+             \n* `/explain`: Explains the selected code\
+             \n* `/help`: Lists the available commands, with what they do\
+             \n* `/logout`: Disconnects your GitHub account"
+        );
+        assert!(commands.resolve("/help", AGENT).is_some());
+    }
+}
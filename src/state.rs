@@ -1,59 +1,102 @@
+use crate::agent::AgentRegistry;
+use crate::capture::Capture;
 use crate::config::Config;
-use crate::copilot_public_keys::load_copilot_public_key;
+use crate::cookie_keys::CookieKeys;
+use crate::copilot_public_keys::CopilotKeyStore;
+use crate::device_flow::DeviceFlows;
+use crate::github::GithubClient;
+use crate::llm::LlmClient;
+use crate::metrics::Metrics;
+use crate::prompt_templates::PromptTemplates;
+use crate::redaction::Redactor;
+use crate::schema_drift::SchemaDrift;
+use crate::thread_store::ThreadStore;
+use crate::toddler_agent::ToddlerAgent;
+use crate::token_store::TokenStore;
 use anyhow::Context;
 use axum::extract::FromRef;
 use axum_extra::extract::cookie::Key;
-use ecdsa::VerifyingKey;
 use oauth2::basic::BasicClient;
-use oauth2::{AuthUrl, ClientId, ClientSecret, RedirectUrl, TokenUrl};
-use p256::NistP256;
+use oauth2::{AuthUrl, ClientId, ClientSecret, DeviceAuthorizationUrl, RedirectUrl, TokenUrl};
+use std::path::Path;
 
 #[derive(Clone)]
 #[allow(clippy::module_name_repetitions)]
 pub struct AppState {
     pub config: Config,
-    pub copilot_public_key: VerifyingKey<NistP256>,
+    pub copilot_public_keys: CopilotKeyStore,
     pub oauth_gh_client: BasicClient,
-    pub cookie_key: Key,
+    pub cookie_keys: CookieKeys,
+    pub github_client: GithubClient,
+    pub llm_client: LlmClient,
+    pub token_store: TokenStore,
+    pub device_flows: DeviceFlows,
+    pub redactor: Redactor,
+    pub metrics: Metrics,
+    pub capture: Option<Capture>,
+    pub schema_drift: SchemaDrift,
+    pub agents: AgentRegistry,
+    pub threads: ThreadStore,
+    pub prompt_templates: PromptTemplates,
 }
 
 impl FromRef<AppState> for Key {
     fn from_ref(state: &AppState) -> Self {
-        state.cookie_key.clone()
+        state.cookie_keys.current().clone()
     }
 }
 
 impl AppState {
     pub async fn new(config: Config) -> anyhow::Result<Self> {
-        let copilot_public_key =
-            load_copilot_public_key("https://api.github.com/meta/public_keys/copilot_api").await?;
-        let oauth_gh_client = create_oauth_gh_client(
-            &config.github_app_client_id,
-            &config.github_app_client_secret,
-            &config.base_url,
-        )?;
-        let cookie_key = Key::generate();
+        config.validate()?;
+        let cookie_keys = CookieKeys::from_config(&config)?;
+        let metrics = Metrics::default();
+        let mut copilot_public_keys =
+            CopilotKeyStore::load(&config.github.copilot_public_keys_url(), metrics.clone())
+                .await?;
+        if let Some(trusted_test_keys_file) = &config.trusted_test_keys_file {
+            copilot_public_keys =
+                copilot_public_keys.with_trusted_keys_file(Path::new(trusted_test_keys_file))?;
+        }
+        let oauth_gh_client = create_oauth_gh_client(&config)?;
+        let github_client = GithubClient::new(&config.github.api_url);
+        let llm_client = LlmClient::new(config.github.copilot_api_url());
+        let redactor = Redactor::with_additional_paths(config.redacted_json_paths.as_deref());
+        let capture = Capture::from_config(&config)?;
+        let threads = ThreadStore::from_config(&config)?;
+        let prompt_templates = PromptTemplates::from_config(&config)?;
         Ok(Self {
             config,
-            copilot_public_key,
+            copilot_public_keys,
             oauth_gh_client,
-            cookie_key,
+            cookie_keys,
+            github_client,
+            llm_client,
+            token_store: TokenStore::default(),
+            device_flows: DeviceFlows::default(),
+            redactor,
+            metrics,
+            capture,
+            schema_drift: SchemaDrift::default(),
+            agents: AgentRegistry::new(ToddlerAgent::default()),
+            threads,
+            prompt_templates,
         })
     }
 }
 
-fn create_oauth_gh_client<T: Into<String>>(
-    client_id: T,
-    client_secret: T,
-    base_url: &str,
-) -> anyhow::Result<BasicClient> {
-    let github_client_id = ClientId::new(client_id.into());
-    let github_client_secret = ClientSecret::new(client_secret.into());
-    let gh_auth_url = AuthUrl::new("https://github.com/login/oauth/authorize".to_string())
+fn create_oauth_gh_client(config: &Config) -> anyhow::Result<BasicClient> {
+    let github_client_id = ClientId::new(config.github_app_client_id.clone());
+    let github_client_secret = ClientSecret::new(config.github_app_client_secret.clone());
+    let gh_auth_url = AuthUrl::new(config.github.authorize_url())
         .context("[config] Invalid authorization endpoint URL")?;
-    let gh_token_url = TokenUrl::new("https://github.com/login/oauth/access_token".to_string())
-        .context("[config] Invalid token endpoint URL")?;
+    let gh_token_url =
+        TokenUrl::new(config.github.token_url()).context("[config] Invalid token endpoint URL")?;
+    let gh_device_authorization_url =
+        DeviceAuthorizationUrl::new(config.github.device_authorization_url())
+            .context("[config] Invalid device authorization endpoint URL")?;
 
+    let base_url = &config.base_url;
     let redirect_url = RedirectUrl::new(format!("{base_url}/auth/gh/authorized"))
         .with_context(|| format!("[config] Unparseable GH redirect URL: {base_url}"))?;
 
@@ -63,5 +106,6 @@ fn create_oauth_gh_client<T: Into<String>>(
         gh_auth_url,
         Some(gh_token_url),
     )
-    .set_redirect_uri(redirect_url))
+    .set_redirect_uri(redirect_url)
+    .set_device_authorization_url(gh_device_authorization_url))
 }
@@ -0,0 +1,475 @@
+use crate::config::Config;
+use crate::messages::{ChatMessage, ChatRequest, Role};
+use anyhow::Context;
+use rusqlite::{params, Connection, OptionalExtension};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tracing::info;
+
+/// Maximum number of threads kept in memory, the least recently updated ones being dropped first.
+const MAX_THREADS_IN_MEMORY: usize = 10_000;
+
+/// What the extension answered in a thread, recorded to be merged back into the history resent by Copilot.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default, Eq, PartialEq)]
+pub struct ThreadEntry {
+    /// Position of the answer among the assistant messages of the thread, from 0
+    pub turn: usize,
+    /// Digest of the user message answered, see [`in_reply_to`]
+    #[serde(default)]
+    pub in_reply_to: String,
+    /// Name of the agent which answered
+    pub agent: String,
+    /// Markdown answer, as streamed to the client
+    pub content: String,
+    pub tool_results: Vec<ToolResult>,
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// Result of a tool called by an agent while answering.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct ToolResult {
+    pub name: String,
+    pub result: serde_json::Value,
+}
+
+/// Answers of the extension keyed by caller and thread, see [`ChatRequest::thread_key`], in memory or in a `SQLite` database.
+///
+/// Threads are scoped to the GitHub user who called the extension, so that a `copilot_thread_id`
+/// sent by someone else never reads their answers.
+#[derive(Clone)]
+pub struct ThreadStore {
+    backend: Backend,
+}
+
+#[derive(Clone)]
+enum Backend {
+    Memory(Arc<Mutex<MemoryThreads>>),
+    /// Queried on the blocking thread pool
+    Sqlite(Arc<Mutex<Connection>>),
+}
+
+/// Caller and thread id.
+type ThreadId = (u64, String);
+
+#[derive(Default)]
+struct MemoryThreads {
+    /// Entries of each thread, with the sequence number of its last update
+    threads: HashMap<ThreadId, (u64, Vec<ThreadEntry>)>,
+    /// Threads by the sequence number of their last update, least recently updated first
+    updates: BTreeMap<u64, ThreadId>,
+    next_update: u64,
+}
+
+impl Default for ThreadStore {
+    fn default() -> Self {
+        Self {
+            backend: Backend::Memory(Arc::default()),
+        }
+    }
+}
+
+impl ThreadStore {
+    /// Store in the `SQLite` database configured by `THREAD_STORE_PATH`, otherwise in memory.
+    pub fn from_config(config: &Config) -> anyhow::Result<Self> {
+        config
+            .thread_store_path
+            .as_deref()
+            .map_or_else(|| Ok(Self::default()), |path| Self::sqlite(Path::new(path)))
+    }
+
+    /// Store in a `SQLite` database, created if missing.
+    pub fn sqlite(path: &Path) -> anyhow::Result<Self> {
+        let connection = Connection::open(path)
+            .with_context(|| format!("[threads] Unable to open {}", path.display()))?;
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS thread_entries (
+                    user_id INTEGER NOT NULL,
+                    thread_id TEXT NOT NULL,
+                    turn INTEGER NOT NULL,
+                    entry TEXT NOT NULL,
+                    PRIMARY KEY (user_id, thread_id, turn)
+                );",
+            )
+            .with_context(|| format!("[threads] Unable to initialize {}", path.display()))?;
+        info!(path = %path.display(), "[threads] Storing threads in SQLite");
+        Ok(Self {
+            backend: Backend::Sqlite(Arc::new(Mutex::new(connection))),
+        })
+    }
+
+    /// Records an answer, replacing any answer recorded for the same turn of the thread.
+    pub async fn record(
+        &self,
+        user_id: u64,
+        thread_id: &str,
+        entry: &ThreadEntry,
+    ) -> anyhow::Result<()> {
+        match &self.backend {
+            Backend::Memory(memory) => {
+                lock(memory).record((user_id, thread_id.to_string()), entry.clone());
+                Ok(())
+            }
+            Backend::Sqlite(connection) => {
+                let (user_id, thread_id) = (i64::try_from(user_id)?, thread_id.to_string());
+                let (turn, entry) = (i64::try_from(entry.turn)?, serde_json::to_string(entry)?);
+                blocking(connection, move |connection| {
+                    connection
+                        .execute(
+                            "INSERT OR REPLACE INTO thread_entries (user_id, thread_id, turn, entry) VALUES (?1, ?2, ?3, ?4)",
+                            params![user_id, thread_id, turn, entry],
+                        )
+                        .context("[threads] Unable to record answer")?;
+                    Ok(())
+                })
+                .await
+            }
+        }
+    }
+
+    /// Answers recorded in a thread, by turn.
+    pub async fn entries(&self, user_id: u64, thread_id: &str) -> anyhow::Result<Vec<ThreadEntry>> {
+        match &self.backend {
+            Backend::Memory(memory) => Ok(lock(memory)
+                .threads
+                .get(&(user_id, thread_id.to_string()))
+                .map(|(_, entries)| entries.clone())
+                .unwrap_or_default()),
+            Backend::Sqlite(connection) => {
+                let (user_id, thread_id) = (i64::try_from(user_id)?, thread_id.to_string());
+                blocking(connection, move |connection| {
+                    let mut statement = connection.prepare_cached(
+                        "SELECT entry FROM thread_entries WHERE user_id = ?1 AND thread_id = ?2 ORDER BY turn",
+                    )?;
+                    let entries = statement
+                        .query_map(params![user_id, thread_id], |row| row.get::<_, String>(0))?
+                        .map(|entry| Ok(serde_json::from_str(&entry?)?))
+                        .collect::<anyhow::Result<_>>()
+                        .context("[threads] Unable to read answers")?;
+                    Ok(entries)
+                })
+                .await
+            }
+        }
+    }
+
+    /// Answer recorded for a turn of a thread.
+    pub async fn entry(
+        &self,
+        user_id: u64,
+        thread_id: &str,
+        turn: usize,
+    ) -> anyhow::Result<Option<ThreadEntry>> {
+        match &self.backend {
+            Backend::Memory(memory) => Ok(lock(memory)
+                .threads
+                .get(&(user_id, thread_id.to_string()))
+                .and_then(|(_, entries)| entries.iter().find(|entry| entry.turn == turn))
+                .cloned()),
+            Backend::Sqlite(connection) => {
+                let (user_id, thread_id) = (i64::try_from(user_id)?, thread_id.to_string());
+                let turn = i64::try_from(turn)?;
+                blocking(connection, move |connection| {
+                    let entry: Option<String> = connection
+                        .query_row(
+                            "SELECT entry FROM thread_entries WHERE user_id = ?1 AND thread_id = ?2 AND turn = ?3",
+                            params![user_id, thread_id, turn],
+                            |row| row.get(0),
+                        )
+                        .optional()
+                        .context("[threads] Unable to read answer")?;
+                    Ok(entry
+                        .map(|entry| serde_json::from_str(&entry))
+                        .transpose()?)
+                })
+                .await
+            }
+        }
+    }
+
+    /// Merges the answers recorded for the thread into the assistant messages of the request,
+    /// filling those resent without content, and returns how many were merged.
+    ///
+    /// An answer is merged into an assistant message only if it replied to the user message preceding it,
+    /// so that a history trimmed by Copilot does not get answers restored at the wrong place.
+    pub async fn merge(
+        &self,
+        user_id: u64,
+        thread_id: &str,
+        request: &mut ChatRequest,
+    ) -> anyhow::Result<usize> {
+        let entries = self.entries(user_id, thread_id).await?;
+        let mut merged = 0;
+        let mut question = String::new();
+        let mut turn = 0;
+        for message in &mut request.messages {
+            match message.role {
+                Role::User if is_question(message) => question = digest(&message.content),
+                Role::Assistant => {
+                    let mut replies = entries
+                        .iter()
+                        .filter(|entry| !question.is_empty() && entry.in_reply_to == question);
+                    // the same question asked several times is told apart by its position
+                    let reply = replies
+                        .clone()
+                        .find(|entry| entry.turn == turn)
+                        .or_else(|| replies.next_back());
+                    if let Some(entry) = reply {
+                        if message.content.trim().is_empty() {
+                            message.content.clone_from(&entry.content);
+                        }
+                        message.recorded = Some(entry.clone());
+                        merged += 1;
+                    }
+                    turn += 1;
+                }
+                _ => {}
+            }
+        }
+        Ok(merged)
+    }
+}
+
+impl MemoryThreads {
+    fn record(&mut self, thread_id: ThreadId, entry: ThreadEntry) {
+        let update = self.next_update;
+        self.next_update += 1;
+        let (last_update, entries) = self.threads.entry(thread_id.clone()).or_default();
+        if !entries.is_empty() {
+            self.updates.remove(last_update);
+        }
+        *last_update = update;
+        entries.retain(|recorded| recorded.turn != entry.turn);
+        entries.push(entry);
+        entries.sort_by_key(|recorded| recorded.turn);
+        self.updates.insert(update, thread_id);
+        if self.threads.len() > MAX_THREADS_IN_MEMORY {
+            if let Some((_, dropped)) = self.updates.pop_first() {
+                self.threads.remove(&dropped);
+            }
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+/// Runs a query on the blocking thread pool, so that disk accesses do not stall the async runtime.
+async fn blocking<T, F>(connection: &Arc<Mutex<Connection>>, query: F) -> anyhow::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&Connection) -> anyhow::Result<T> + Send + 'static,
+{
+    let connection = connection.clone();
+    tokio::task::spawn_blocking(move || query(&lock(&connection))).await?
+}
+
+/// Digest of the last user message of the request, which the next assistant message answers.
+#[must_use]
+pub fn in_reply_to(request: &ChatRequest) -> String {
+    request
+        .messages
+        .iter()
+        .rfind(|message| message.role == Role::User && is_question(message))
+        .map(|message| digest(&message.content))
+        .unwrap_or_default()
+}
+
+/// User messages with content, other than the pseudo messages added by Copilot.
+fn is_question(message: &ChatMessage) -> bool {
+    message.name.is_none() && !message.content.trim().is_empty()
+}
+
+fn digest(content: &str) -> String {
+    hex::encode(Sha256::digest(content.trim()))
+}
+
+/// Turn answered by the next assistant message of the request.
+#[must_use]
+pub fn next_turn(request: &ChatRequest) -> usize {
+    request
+        .messages
+        .iter()
+        .filter(|message| message.role == Role::Assistant)
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::messages::{ChatMessage, ChatRequest, Role};
+    use crate::thread_store::{in_reply_to, next_turn, ThreadEntry, ThreadStore, ToolResult};
+    use std::fs;
+
+    fn entry(turn: usize, content: &str) -> ThreadEntry {
+        ThreadEntry {
+            turn,
+            agent: "toddler".to_string(),
+            content: content.to_string(),
+            ..ThreadEntry::default()
+        }
+    }
+
+    fn answer(turn: usize, question: &str, content: &str) -> ThreadEntry {
+        ThreadEntry {
+            in_reply_to: super::digest(question),
+            ..entry(turn, content)
+        }
+    }
+
+    fn request(messages: &[(Role, &str)]) -> ChatRequest {
+        ChatRequest {
+            copilot_thread_id: "thread".to_string(),
+            messages: messages
+                .iter()
+                .map(|(role, content)| ChatMessage {
+                    role: role.clone(),
+                    content: content.to_string(),
+                    name: None,
+                    copilot_references: vec![],
+                    recorded: None,
+                })
+                .collect(),
+            agent: None,
+        }
+    }
+
+    async fn answers_are_merged_into_empty_assistant_messages(
+        store: &ThreadStore,
+    ) -> anyhow::Result<()> {
+        let payload = fs::read_to_string("samples/chat_request_from_vs_code_with_selection.json")?;
+        let mut request = ChatRequest::parse(&payload)?;
+        let thread_id = request.copilot_thread_id.clone();
+        assert_eq!(next_turn(&request), 1);
+        assert_eq!(request.messages[1].content, "");
+
+        store
+            .record(1, &thread_id, &answer(0, "help with this file", "draft"))
+            .await?;
+        store
+            .record(
+                1,
+                &thread_id,
+                &answer(0, "help with this file", "This file parses requests."),
+            )
+            .await?;
+        store
+            .record(
+                1,
+                "another thread",
+                &answer(0, "help with this file", "Unrelated"),
+            )
+            .await?;
+        // the same thread id sent by another caller is another thread
+        let someone_else = answer(0, "help with this file", "Someone else's answer");
+        store.record(2, &thread_id, &someone_else).await?;
+        assert_eq!(store.merge(1, &thread_id, &mut request).await?, 1);
+        assert_eq!(request.messages[1].content, "This file parses requests.");
+        // messages resent with their content are kept as is
+        request.messages[1].content = "Edited".to_string();
+        assert_eq!(store.merge(1, &thread_id, &mut request).await?, 1);
+        assert_eq!(request.messages[1].content, "Edited");
+        assert_eq!(store.entries(2, &thread_id).await?, vec![someone_else]);
+        assert_eq!(store.entries(3, &thread_id).await?, vec![]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn answers_are_merged_from_memory() -> anyhow::Result<()> {
+        answers_are_merged_into_empty_assistant_messages(&ThreadStore::default()).await
+    }
+
+    #[tokio::test]
+    async fn answers_are_merged_from_sqlite() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("threads.sqlite");
+        answers_are_merged_into_empty_assistant_messages(&ThreadStore::sqlite(&path)?).await?;
+
+        // entries survive a restart, with their tool results and metadata
+        let mut entry = entry(1, "Done");
+        entry.tool_results.push(ToolResult {
+            name: "search".to_string(),
+            result: serde_json::json!({ "hits": 2 }),
+        });
+        entry
+            .metadata
+            .insert("model".to_string(), "gpt-4o".to_string());
+        ThreadStore::sqlite(&path)?
+            .record(1, "thread", &entry)
+            .await?;
+        let reopened = ThreadStore::sqlite(&path)?;
+        assert_eq!(reopened.entry(1, "thread", 1).await?, Some(entry));
+        assert_eq!(reopened.entries(1, "thread").await?.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn answers_are_merged_into_trimmed_histories() -> anyhow::Result<()> {
+        let store = ThreadStore::default();
+        let thread = request(&[(Role::User, "a"), (Role::Assistant, ""), (Role::User, "b")]);
+        assert_eq!(in_reply_to(&thread), super::digest("b"));
+        let mut searched = answer(1, "b", "Found it");
+        searched.tool_results.push(ToolResult {
+            name: "search".to_string(),
+            result: serde_json::json!({ "hits": 2 }),
+        });
+        searched
+            .metadata
+            .insert("integration_id".to_string(), "vscode-chat".to_string());
+        store
+            .record(1, "thread", &answer(0, "a", "Answer to a"))
+            .await?;
+        store.record(1, "thread", &searched).await?;
+
+        // the oldest turn was trimmed by Copilot, the answer to b is still restored after b
+        let mut trimmed = request(&[(Role::User, "b"), (Role::Assistant, ""), (Role::User, "c")]);
+        assert_eq!(store.merge(1, "thread", &mut trimmed).await?, 1);
+        assert_eq!(trimmed.messages[1].content, "Found it");
+        assert_eq!(trimmed.messages[1].recorded, Some(searched));
+
+        // answers are not restored after another question
+        let mut other = request(&[(Role::User, "d"), (Role::Assistant, ""), (Role::User, "e")]);
+        assert_eq!(store.merge(1, "thread", &mut other).await?, 0);
+        assert_eq!(other.messages[1].content, "");
+
+        // the same question asked twice is told apart by its position
+        store
+            .record(1, "thread", &answer(2, "a", "Answer to a, again"))
+            .await?;
+        let mut again = request(&[
+            (Role::User, "a"),
+            (Role::Assistant, ""),
+            (Role::User, "b"),
+            (Role::Assistant, ""),
+            (Role::User, "a"),
+            (Role::Assistant, ""),
+        ]);
+        assert_eq!(store.merge(1, "thread", &mut again).await?, 3);
+        assert_eq!(again.messages[1].content, "Answer to a");
+        assert_eq!(again.messages[5].content, "Answer to a, again");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn least_recently_updated_threads_are_dropped_from_memory() -> anyhow::Result<()> {
+        let store = ThreadStore::default();
+        for thread in 0..=super::MAX_THREADS_IN_MEMORY {
+            store
+                .record(1, &thread.to_string(), &entry(0, "answer"))
+                .await?;
+            if thread == 1 {
+                // updating the first thread keeps it over the second one
+                store.record(1, "0", &entry(1, "again")).await?;
+            }
+        }
+        store.record(1, "0", &entry(2, "still there")).await?;
+        assert_eq!(store.entries(1, "0").await?.len(), 3);
+        assert_eq!(store.entries(1, "1").await?, vec![]);
+        assert_eq!(store.entries(1, "2").await?.len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,259 @@
+use crate::agent::{message, AgentContext, AgentError, AgentEvent, AgentEvents, CopilotAgent};
+use crate::logout::revoke_user_tokens;
+use crate::oauth::valid_token;
+use crate::prompt::{Dropped, Prompt, PromptBuilder};
+use crate::prompt_templates::PromptVariables;
+use crate::slash_commands::SlashCommands;
+use futures::future::BoxFuture;
+use futures::{stream, StreamExt};
+use oauth2::url::Url;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::{debug, error};
+
+const NAME: &str = "toddler";
+
+/// The extension answering with the Copilot model, once the GitHub account of the caller is connected.
+pub struct ToddlerAgent {
+    commands: SlashCommands,
+}
+
+impl Default for ToddlerAgent {
+    fn default() -> Self {
+        Self {
+            commands: SlashCommands::default()
+                .with_command(
+                    "logout",
+                    "Disconnects your GitHub account from this extension",
+                    logout,
+                )
+                .with_command(
+                    "explain",
+                    "Explains the selected code, or the current file",
+                    explain,
+                ),
+        }
+    }
+}
+
+impl CopilotAgent for ToddlerAgent {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn respond(&self, context: AgentContext) -> BoxFuture<'_, Result<AgentEvents, AgentError>> {
+        Box::pin(async move {
+            let invocation = context.request.messages.last().and_then(|message| {
+                self.commands
+                    .resolve(&message.content, context.request.agent.as_deref())
+            });
+            match invocation {
+                Some(invocation) => {
+                    debug!(
+                        command = invocation.name,
+                        "[agent] toddler: Answering command"
+                    );
+                    invocation.run(context).await
+                }
+                None => respond(context, None).await,
+            }
+        })
+    }
+}
+
+/// Deletes the stored tokens of the caller and revokes the grant on GitHub.
+async fn logout(context: AgentContext, _args: String) -> Result<AgentEvents, AgentError> {
+    let answer = match revoke_user_tokens(&context.state, context.user.id, "chat command").await {
+        Ok(true) => "Your GitHub account has been disconnected from this extension.",
+        Ok(false) => "Your GitHub account is not connected to this extension.",
+        Err(err) => {
+            error!(error = ?err, "[agent] toddler: Unable to revoke grant");
+            "Your GitHub account has been disconnected from this extension, \
+             but the authorization could not be revoked on GitHub, \
+             please revoke it from your GitHub settings."
+        }
+    };
+    Ok(message(answer))
+}
+
+/// Answers with the template of the `toddler.explain` prompt.
+async fn explain(context: AgentContext, args: String) -> Result<AgentEvents, AgentError> {
+    respond(context, Some(("explain", &args))).await
+}
+
+/// Answers with the model, the system prompt being the template of the command, if any, or of the agent.
+async fn respond(
+    context: AgentContext,
+    command: Option<(&str, &str)>,
+) -> Result<AgentEvents, AgentError> {
+    let AgentContext {
+        state,
+        request,
+        github_token,
+        user,
+        ..
+    } = context;
+    if valid_token(&state, user.id).await.is_none() {
+        debug!(
+            login = user.login,
+            "[agent] toddler: No valid token stored, prompting to connect"
+        );
+        return Ok(message(connect_account_message(
+            &state.config.base_url,
+            request.current_url(),
+        )));
+    }
+
+    let mut variables = PromptVariables::new(NAME, &user.login, &request);
+    if let Some((command, args)) = command {
+        variables = variables.with_command(command, args);
+    }
+    let system = state.prompt_templates.render_or_fallback(&variables);
+    let mut builder = PromptBuilder::new(state.config.prompt_max_tokens);
+    if let Some(system) = system {
+        builder = builder.system(system);
+    }
+    let prompt = match builder.build(&request.conversation()) {
+        Ok(prompt) => prompt,
+        Err(err) => {
+            error!(error = %err, "[agent] toddler: Unable to fit the prompt");
+            return Ok(message(
+                "The instructions of this extension do not fit in the context window of the model, \
+                 please ask its administrator to raise `PROMPT_MAX_TOKENS` or shorten its prompt templates.",
+            ));
+        }
+    };
+    debug!(
+        tokens = prompt.tokens,
+        dropped = prompt.dropped.len(),
+        "[agent] toddler: Prompt built"
+    );
+    let Prompt {
+        messages, dropped, ..
+    } = prompt;
+    let started_at = Instant::now();
+    let chunks = state
+        .llm_client
+        .stream_completion(&github_token, &messages)
+        .await
+        .inspect_err(|_| state.metrics.llm_latency(started_at.elapsed(), false))
+        .map_err(AgentError::Upstream)?;
+    let metrics = state.metrics.clone();
+    let failed = Arc::new(AtomicBool::new(false));
+    let completion = {
+        let (metrics, failed) = (metrics.clone(), failed.clone());
+        stream::once(async move {
+            metrics.llm_latency(started_at.elapsed(), !failed.load(Ordering::Relaxed));
+            None
+        })
+        .filter_map(std::future::ready)
+    };
+    Ok(chunks
+        .map(move |chunk| match chunk {
+            Ok(chunk) => {
+                metrics.llm_streamed_tokens(1);
+                AgentEvent::Text(chunk)
+            }
+            Err(err) => {
+                error!(error = ?err, "[agent] toddler: Completion failed");
+                failed.store(true, Ordering::Relaxed);
+                AgentEvent::Error {
+                    code: "completion_failed".to_string(),
+                    message: "The answer could not be completed, please try again.".to_string(),
+                }
+            }
+        })
+        // the latency is recorded once the completion is entirely streamed
+        .chain(completion)
+        .chain(stream::iter(dropped_note(&dropped).map(AgentEvent::Text)))
+        .boxed())
+}
+
+/// Note ending the answer when parts of the conversation were left out of the prompt.
+fn dropped_note(dropped: &[Dropped]) -> Option<String> {
+    (!dropped.is_empty()).then(|| {
+        let dropped: Vec<String> = dropped.iter().map(ToString::to_string).collect();
+        format!(
+            "\n\n_To fit the context window of the model, this answer left out {}._",
+            dropped.join(", ")
+        )
+    })
+}
+
+fn connect_account_message(base_url: &str, return_to: Option<&str>) -> String {
+    let authorization_url = format!("{base_url}/auth/authorization");
+    let link = match return_to {
+        Some(return_to) => Url::parse_with_params(&authorization_url, [("return_to", return_to)])
+            .map_or(authorization_url, String::from),
+        None => authorization_url,
+    };
+    format!(
+        "Your GitHub account is not connected to this extension yet, or its authorization has expired.\n\n\
+         Please [connect your account]({link}), then send your message again."
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prompt::Dropped;
+    use crate::toddler_agent::{connect_account_message, dropped_note, ToddlerAgent};
+
+    #[test]
+    fn logout_command_is_recognized_with_or_without_mention() {
+        let agent = ToddlerAgent::default();
+        let command = |content| {
+            agent
+                .commands
+                .resolve(content, Some("mais-arreeeeeeeeteuuuu"))
+                .map(|invocation| invocation.name)
+        };
+        assert_eq!(command("/logout").as_deref(), Some("logout"));
+        assert_eq!(
+            command("@mais-arreeeeeeeeteuuuu  /logout ").as_deref(),
+            Some("logout")
+        );
+        assert_eq!(command("@mais-arreeeeeeeeteuuuu"), None);
+        assert_eq!(command("how do I /logout?"), None);
+    }
+
+    #[test]
+    fn help_lists_the_logout_command() {
+        assert!(ToddlerAgent::default()
+            .commands
+            .help()
+            .contains("* `/logout`: Disconnects your GitHub account from this extension"));
+    }
+
+    #[test]
+    fn connect_account_message_links_to_authorization() {
+        let message = connect_account_message(
+            "https://toddler.example.com",
+            Some("https://github.com/ledoyen/toddler-copilot-extension/actions"),
+        );
+        assert!(message.contains(
+            "(https://toddler.example.com/auth/authorization?return_to=https%3A%2F%2Fgithub.com%2Fledoyen%2Ftoddler-copilot-extension%2Factions)"
+        ));
+
+        let message = connect_account_message("https://toddler.example.com", None);
+        assert!(message.contains("(https://toddler.example.com/auth/authorization)"));
+    }
+
+    #[test]
+    fn dropped_parts_are_noted_after_the_answer() {
+        assert_eq!(dropped_note(&[]), None);
+        assert_eq!(
+            dropped_note(&[
+                Dropped::Reference {
+                    id: "lib.rs".to_string()
+                },
+                Dropped::Turns(3)
+            ])
+            .as_deref(),
+            Some(
+                "\n\n_To fit the context window of the model, this answer left out \
+                 `lib.rs`, the 3 oldest messages of the conversation._"
+            )
+        );
+    }
+}
@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use time::OffsetDateTime;
+
+/// Tokens obtained through the OAuth flow, kept in memory and keyed by GitHub user id:
+/// users have to connect their account again after a restart.
+#[derive(Clone, Default)]
+pub struct TokenStore {
+    tokens: Arc<RwLock<HashMap<u64, StoredToken>>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct StoredToken {
+    pub login: String,
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    pub expires_at: Option<OffsetDateTime>,
+}
+
+impl StoredToken {
+    #[must_use]
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= OffsetDateTime::now_utc())
+    }
+}
+
+impl TokenStore {
+    pub fn insert(&self, user_id: u64, token: StoredToken) {
+        if let Ok(mut tokens) = self.tokens.write() {
+            tokens.insert(user_id, token);
+        }
+    }
+
+    #[must_use]
+    pub fn remove(&self, user_id: u64) -> Option<StoredToken> {
+        self.tokens
+            .write()
+            .ok()
+            .and_then(|mut tokens| tokens.remove(&user_id))
+    }
+
+    /// Returns the stored token of the given user, even expired.
+    #[must_use]
+    pub fn get(&self, user_id: u64) -> Option<StoredToken> {
+        self.tokens
+            .read()
+            .ok()
+            .and_then(|tokens| tokens.get(&user_id).cloned())
+    }
+
+    /// Returns the stored token of the given user, unless it is missing or expired.
+    #[must_use]
+    pub fn get_valid(&self, user_id: u64) -> Option<StoredToken> {
+        self.get(user_id).filter(|token| !token.is_expired())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::token_store::{StoredToken, TokenStore};
+    use time::{Duration, OffsetDateTime};
+
+    fn token(expires_at: Option<OffsetDateTime>) -> StoredToken {
+        StoredToken {
+            login: "ledoyen".to_string(),
+            access_token: "ghu_token".to_string(),
+            refresh_token: None,
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn get_valid_ignores_missing_and_expired_tokens() {
+        let store = TokenStore::default();
+        assert!(store.get_valid(1).is_none());
+
+        store.insert(
+            1,
+            token(Some(OffsetDateTime::now_utc() - Duration::minutes(1))),
+        );
+        assert!(store.get_valid(1).is_none());
+
+        store.insert(
+            1,
+            token(Some(OffsetDateTime::now_utc() + Duration::hours(8))),
+        );
+        assert!(store.get_valid(1).is_some());
+
+        store.insert(2, token(None));
+        assert!(store.get_valid(2).is_some());
+    }
+}
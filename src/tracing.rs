@@ -1,11 +1,48 @@
-use colored::Colorize;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+use crate::config::{Config, LogFormat};
+use anyhow::Context;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::trace::{Sampler, TracerProvider};
+use opentelemetry_sdk::{runtime, Resource};
+use std::fmt;
+use std::io::IsTerminal;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tracing::field::{Field, Visit};
+use tracing::{info, Event, Subscriber};
+use tracing_subscriber::fmt::format::{JsonFields, Writer};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields, MakeWriter};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
 
-pub fn setup() -> anyhow::Result<()> {
-    colored::control::set_override(true); // always apply color
+pub fn setup(config: &Config) -> anyhow::Result<()> {
+    // only apply color when a human is watching
+    let ansi = std::io::stdout().is_terminal();
+    colored::control::set_override(ansi);
+
+    let otel_layer = match &config.otel_exporter_otlp_endpoint {
+        Some(endpoint) => {
+            let provider = tracer_provider(
+                endpoint,
+                &config.otel_service_name,
+                config.otel_sampling_ratio,
+            )?;
+            opentelemetry::global::set_tracer_provider(provider.clone());
+            Some(
+                tracing_opentelemetry::layer()
+                    .with_tracer(provider.tracer("toddler-copilot-extension")),
+            )
+        }
+        None => None,
+    };
 
     tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer()) // .without_time()
+        .with(otel_layer)
+        .with((config.log_format == LogFormat::Pretty).then(|| {
+            tracing_subscriber::fmt::layer().with_ansi(ansi) // .without_time()
+        }))
+        .with((config.log_format == LogFormat::Json).then(|| json_layer(std::io::stdout)))
         .with(
             // let user override RUST_LOG in local run if they want to
             EnvFilter::try_from_default_env()
@@ -14,6 +51,242 @@ pub fn setup() -> anyhow::Result<()> {
         )
         .init();
 
-    println!("{}", "Custom tracing subscriber is initialized!".yellow());
+    info!(log_format = ?config.log_format, "[tracing] Subscriber initialized");
     Ok(())
 }
+
+/// Exports spans in batches to an OTLP/HTTP collector, sampling the traces started here with the given ratio.
+///
+/// Must be called from a Tokio runtime.
+pub fn tracer_provider(
+    endpoint: &str,
+    service_name: &str,
+    sampling_ratio: f64,
+) -> anyhow::Result<TracerProvider> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{endpoint}/v1/traces"))
+        .build()
+        .context("[config] Unable to create the OTLP exporter")?;
+    Ok(TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            sampling_ratio,
+        ))))
+        .with_resource(Resource::new([KeyValue::new(
+            "service.name",
+            service_name.to_string(),
+        )]))
+        .build())
+}
+
+/// Flushes the spans not exported yet, to be called before exiting.
+pub async fn shutdown() {
+    if let Err(err) =
+        tokio::task::spawn_blocking(opentelemetry::global::shutdown_tracer_provider).await
+    {
+        eprintln!("Unable to shut the tracer provider down: {err}");
+    }
+}
+
+/// One JSON object per line, with the fields of the enclosing spans flattened next to the ones of the event.
+#[must_use]
+pub fn json_layer<S, W>(writer: W) -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    tracing_subscriber::fmt::layer()
+        .with_ansi(false)
+        .with_writer(writer)
+        .fmt_fields(JsonFields::new())
+        .event_format(FlattenedJson)
+}
+
+/// Formats events as JSON objects with stable field names.
+///
+/// `timestamp`, `level`, `target`, `span` (name of the innermost span), `message`,
+/// then the fields of spans, from the outermost to the innermost, and the ones of the event.
+pub struct FlattenedJson;
+
+impl<S, N> FormatEvent<S, N> for FlattenedJson
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let metadata = event.metadata();
+        let mut fields = serde_json::Map::new();
+        fields.insert(
+            "timestamp".to_string(),
+            OffsetDateTime::now_utc()
+                .format(&Rfc3339)
+                .unwrap_or_default()
+                .into(),
+        );
+        fields.insert("level".to_string(), metadata.level().as_str().into());
+        fields.insert("target".to_string(), metadata.target().into());
+
+        if let Some(scope) = ctx.event_scope() {
+            for span in scope.from_root() {
+                fields.insert("span".to_string(), span.name().into());
+                let extensions = span.extensions();
+                let span_fields = extensions
+                    .get::<FormattedFields<N>>()
+                    .and_then(|formatted| {
+                        serde_json::from_str::<serde_json::Map<_, _>>(formatted).ok()
+                    });
+                fields.extend(span_fields.into_iter().flatten());
+            }
+        }
+
+        event.record(&mut JsonVisitor(&mut fields));
+        writeln!(writer, "{}", serde_json::Value::Object(fields))
+    }
+}
+
+struct JsonVisitor<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{value:?}").into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tracing::{json_layer, tracer_provider};
+    use axum::body::Bytes;
+    use axum::routing::post;
+    use axum::Router;
+    use opentelemetry::trace::TracerProvider as _;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use tokio::sync::mpsc;
+    use tracing_subscriber::fmt::MakeWriter;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0
+                .lock()
+                .map_err(|_| std::io::Error::other("poisoned"))?
+                .extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Buffer {
+        type Writer = Self;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    #[test]
+    fn span_fields_are_flattened_into_events() -> anyhow::Result<()> {
+        let buffer = Buffer::default();
+        let subscriber = tracing_subscriber::registry().with(json_layer(buffer.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!(
+                "agent",
+                copilot_thread_id = "f61a1e05",
+                integration_id = "vscode-chat"
+            );
+            let _guard = span.enter();
+            let inner = tracing::info_span!("command", login = "ledoyen");
+            let _inner_guard = inner.enter();
+            tracing::info!(count = 3, "Hello");
+        });
+
+        let output = String::from_utf8(
+            buffer
+                .0
+                .lock()
+                .map_err(|_| anyhow::anyhow!("poisoned"))?
+                .clone(),
+        )?;
+        let event: serde_json::Value = serde_json::from_str(output.trim())?;
+        assert_eq!(event["level"], "INFO");
+        assert_eq!(event["message"], "Hello");
+        assert_eq!(event["span"], "command");
+        assert_eq!(event["copilot_thread_id"], "f61a1e05");
+        assert_eq!(event["integration_id"], "vscode-chat");
+        assert_eq!(event["login"], "ledoyen");
+        assert_eq!(event["count"], 3);
+        assert!(event["timestamp"].is_string());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn spans_are_exported_to_the_collector() -> anyhow::Result<()> {
+        let (sender, mut exports) = mpsc::unbounded_channel::<Bytes>();
+        let collector = Router::new().route(
+            "/v1/traces",
+            post(move |body: Bytes| async move {
+                let _ = sender.send(body);
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let endpoint = format!("http://{}", listener.local_addr()?);
+        tokio::spawn(async move { axum::serve(listener, collector).await });
+
+        let provider = tracer_provider(&endpoint, "toddler-test", 1.0)?;
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("agent", integration_id = "vscode-chat");
+            let _guard = span.enter();
+            tracing::info_span!("signature_verification").in_scope(|| {});
+        });
+        tokio::task::spawn_blocking(move || provider.force_flush()).await?;
+
+        let export = tokio::time::timeout(std::time::Duration::from_secs(5), exports.recv())
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("collector stopped"))?;
+        let export = String::from_utf8_lossy(&export);
+        for expected in [
+            "toddler-test",
+            "agent",
+            "signature_verification",
+            "vscode-chat",
+        ] {
+            assert!(export.contains(expected), "{expected} not exported");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,140 @@
+use anyhow::Context;
+
+/// Events sent by GitHub to the app webhook, only the ones handled by the app are typed.
+#[derive(Debug, Eq, PartialEq)]
+pub enum WebhookEvent {
+    Ping(PingEvent),
+    Installation(InstallationEvent),
+    GithubAppAuthorization(GithubAppAuthorizationEvent),
+    Unsupported(String),
+}
+
+impl WebhookEvent {
+    /// Parses the payload according to the event name sent in the `X-GitHub-Event` header.
+    pub fn parse(event_name: &str, payload: &[u8]) -> anyhow::Result<Self> {
+        let event = match event_name {
+            "ping" => Self::Ping(parse_payload(event_name, payload)?),
+            "installation" => Self::Installation(parse_payload(event_name, payload)?),
+            "github_app_authorization" => {
+                Self::GithubAppAuthorization(parse_payload(event_name, payload)?)
+            }
+            _ => Self::Unsupported(event_name.to_string()),
+        };
+        Ok(event)
+    }
+}
+
+fn parse_payload<T: serde::de::DeserializeOwned>(
+    event_name: &str,
+    payload: &[u8],
+) -> anyhow::Result<T> {
+    let deserializer = &mut serde_json::Deserializer::from_slice(payload);
+    serde_path_to_error::deserialize(deserializer)
+        .with_context(|| format!("[webhook] Unable to parse '{event_name}' event"))
+}
+
+#[derive(serde::Deserialize, Eq, PartialEq, Debug)]
+pub struct PingEvent {
+    pub zen: String,
+    pub hook_id: u64,
+}
+
+#[derive(serde::Deserialize, Eq, PartialEq, Debug)]
+pub struct InstallationEvent {
+    pub action: InstallationAction,
+    pub installation: Installation,
+    pub sender: Account,
+}
+
+#[derive(serde::Deserialize, Eq, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum InstallationAction {
+    Created,
+    Deleted,
+    Suspend,
+    Unsuspend,
+    NewPermissionsAccepted,
+    #[serde(other)]
+    Other,
+}
+
+#[derive(serde::Deserialize, Eq, PartialEq, Debug)]
+pub struct Installation {
+    pub id: u64,
+    pub account: Account,
+}
+
+#[derive(serde::Deserialize, Eq, PartialEq, Debug)]
+pub struct GithubAppAuthorizationEvent {
+    pub action: String,
+    pub sender: Account,
+}
+
+#[derive(serde::Deserialize, Eq, PartialEq, Debug)]
+pub struct Account {
+    pub id: u64,
+    pub login: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::webhook_events::{
+        Account, GithubAppAuthorizationEvent, Installation, InstallationAction, InstallationEvent,
+        WebhookEvent,
+    };
+
+    #[test]
+    fn parse_installation_deleted() -> anyhow::Result<()> {
+        let payload = r#"{"action":"deleted","installation":{"id":58197416,"account":{"login":"ledoyen","id":5427447,"type":"User"},"app_id":1077455},"repositories":[],"sender":{"login":"ledoyen","id":5427447,"type":"User"}}"#;
+        assert_eq!(
+            WebhookEvent::parse("installation", payload.as_bytes())?,
+            WebhookEvent::Installation(InstallationEvent {
+                action: InstallationAction::Deleted,
+                installation: Installation {
+                    id: 58197416,
+                    account: Account {
+                        id: 5427447,
+                        login: "ledoyen".to_string(),
+                    },
+                },
+                sender: Account {
+                    id: 5427447,
+                    login: "ledoyen".to_string(),
+                },
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_github_app_authorization_revoked() -> anyhow::Result<()> {
+        let payload =
+            r#"{"action":"revoked","sender":{"login":"ledoyen","id":5427447,"type":"User"}}"#;
+        assert_eq!(
+            WebhookEvent::parse("github_app_authorization", payload.as_bytes())?,
+            WebhookEvent::GithubAppAuthorization(GithubAppAuthorizationEvent {
+                action: "revoked".to_string(),
+                sender: Account {
+                    id: 5427447,
+                    login: "ledoyen".to_string(),
+                },
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_reports_path_of_invalid_fields() {
+        let err = WebhookEvent::parse(
+            "installation",
+            br#"{"action":"created","installation":{"id":"nope"}}"#,
+        )
+        .err()
+        .map(|err| format!("{err:#}"));
+        assert!(err.is_some_and(|err| err.contains("installation.id")));
+        assert_eq!(
+            WebhookEvent::parse("star", b"{}").ok(),
+            Some(WebhookEvent::Unsupported("star".to_string()))
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use crate::state::AppState;
+use crate::webhook_events::{InstallationAction, WebhookEvent};
+use anyhow::{anyhow, Context};
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tracing::{error, info, warn};
+
+#[allow(clippy::unused_async)]
+pub async fn github_webhook(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> StatusCode {
+    let Some(secret) = state.config.github_webhook_secret.as_deref() else {
+        warn!(
+            "[http] github_webhook: Received a webhook but GITHUB_WEBHOOK_SECRET is not configured"
+        );
+        return StatusCode::NOT_FOUND;
+    };
+    let signature = headers
+        .get("x-hub-signature-256")
+        .and_then(|value| value.to_str().ok());
+    if let Err(err) = verify_signature(secret, signature, &body) {
+        warn!(error = ?err, "[http] github_webhook: Invalid signature");
+        return StatusCode::UNAUTHORIZED;
+    }
+
+    let Some(event_name) = headers
+        .get("x-github-event")
+        .and_then(|value| value.to_str().ok())
+    else {
+        warn!("[http] github_webhook: Missing header 'x-github-event'");
+        return StatusCode::BAD_REQUEST;
+    };
+    match WebhookEvent::parse(event_name, &body) {
+        Ok(event) => {
+            handle_event(&state, event);
+            StatusCode::NO_CONTENT
+        }
+        Err(err) => {
+            error!(error = ?err, "[http] github_webhook");
+            StatusCode::BAD_REQUEST
+        }
+    }
+}
+
+/// Verifies the `X-Hub-Signature-256` header, an HMAC-SHA256 of the body keyed with the webhook secret.
+pub fn verify_signature(secret: &str, signature: Option<&str>, body: &[u8]) -> anyhow::Result<()> {
+    let signature = signature.ok_or_else(|| anyhow!("Missing header 'x-hub-signature-256'"))?;
+    let hex_signature = signature
+        .strip_prefix("sha256=")
+        .ok_or_else(|| anyhow!("Signature is not prefixed by 'sha256='"))?;
+    let signature = hex::decode(hex_signature).context("Error while hex decoding signature")?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())?;
+    mac.update(body);
+    mac.verify_slice(&signature)
+        .context("Signature does not match body")?;
+    Ok(())
+}
+
+fn handle_event(state: &AppState, event: WebhookEvent) {
+    match event {
+        WebhookEvent::Ping(ping) => {
+            info!(hook_id = ping.hook_id, zen = ping.zen, "[webhook] ping");
+        }
+        WebhookEvent::Installation(installation) => {
+            info!(
+                installation_id = installation.installation.id,
+                account = installation.installation.account.login,
+                sender = installation.sender.login,
+                action = ?installation.action,
+                "[webhook] installation"
+            );
+            if installation.action == InstallationAction::Deleted {
+                purge_tokens(state, installation.sender.id, "app uninstalled");
+            }
+        }
+        WebhookEvent::GithubAppAuthorization(authorization) => {
+            if authorization.action == "revoked" {
+                purge_tokens(
+                    state,
+                    authorization.sender.id,
+                    "authorization revoked on GitHub",
+                );
+            }
+        }
+        WebhookEvent::Unsupported(event_name) => {
+            info!(event_name, "[webhook] Ignoring unsupported event");
+        }
+    }
+}
+
+/// The grant is already gone on GitHub side, only local tokens have to be deleted.
+fn purge_tokens(state: &AppState, user_id: u64, reason: &str) {
+    if let Some(token) = state.token_store.remove(user_id) {
+        info!(
+            target: "audit",
+            user_id,
+            login = token.login,
+            reason,
+            revoked_on_github = true,
+            "Token revoked"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::webhooks::verify_signature;
+
+    // Example from https://docs.github.com/en/webhooks/using-webhooks/validating-webhook-deliveries
+    const SECRET: &str = "It's a Secret to Everybody";
+    const SIGNATURE: &str =
+        "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+
+    #[test]
+    fn verify_signature_of_documented_example() {
+        assert!(verify_signature(SECRET, Some(SIGNATURE), b"Hello, World!").is_ok());
+    }
+
+    #[test]
+    fn verify_signature_rejects_tampered_or_malformed_signatures() {
+        assert!(verify_signature(SECRET, Some(SIGNATURE), b"Hello, World?").is_err());
+        assert!(verify_signature("another secret", Some(SIGNATURE), b"Hello, World!").is_err());
+        assert!(verify_signature(SECRET, Some(&SIGNATURE[7..]), b"Hello, World!").is_err());
+        assert!(verify_signature(SECRET, Some("sha256=zz"), b"Hello, World!").is_err());
+        assert!(verify_signature(SECRET, None, b"Hello, World!").is_err());
+    }
+}